@@ -5,7 +5,7 @@ extern crate kvs;
 extern crate lazy_static;
 extern crate rand;
 
-use crate::kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use crate::kvs::{KvStore, KvsEngine, SledKvsEngine};
 use crate::rand::Rng;
 use criterion::Criterion;
 
@@ -29,60 +29,52 @@ lazy_static! {
     static ref PAIRS: Vec<(String, String)> = generate_random_string_pairs(100, 100000);
 }
 
-fn kvs_write(c: &mut Criterion) -> Result<()> {
-    let mut kvstore = KvStore::open(".")?;
+fn kvs_write(c: &mut Criterion) {
+    let kvstore = KvStore::open(".").unwrap();
     c.bench_function("kvs_write", move |b| {
         let pairs = PAIRS.clone();
         b.iter(|| {
             for (key, value) in pairs.clone() {
-                kvstore.set(key, value);
+                kvstore.set(key, value).unwrap();
             }
         });
     });
-
-    Ok(())
 }
 
-fn kvs_read(c: &mut Criterion) -> Result<()> {
-    let mut kvstore = KvStore::open(".")?;
+fn kvs_read(c: &mut Criterion) {
+    let kvstore = KvStore::open(".").unwrap();
     c.bench_function("kvs_read", move |b| {
         let pairs = PAIRS.clone();
         b.iter(|| {
             for (key, _) in pairs.clone() {
-                kvstore.get(key);
+                kvstore.get(key).unwrap();
             }
         });
     });
-
-    Ok(())
 }
 
-fn sled_write(c: &mut Criterion) -> Result<()> {
-    let mut sled = SledKvsEngine::open(".")?;
+fn sled_write(c: &mut Criterion) {
+    let sled = SledKvsEngine::open(".").unwrap();
     c.bench_function("sled_write", move |b| {
         let pairs = PAIRS.clone();
         b.iter(|| {
             for (key, value) in pairs.clone() {
-                sled.set(key, value);
+                sled.set(key, value).unwrap();
             }
         });
     });
-
-    Ok(())
 }
 
-fn sled_read(c: &mut Criterion) -> Result<()> {
-    let mut sled = SledKvsEngine::open(".")?;
+fn sled_read(c: &mut Criterion) {
+    let sled = SledKvsEngine::open(".").unwrap();
     c.bench_function("sled_read", move |b| {
         let pairs = PAIRS.clone();
         b.iter(|| {
             for (key, _) in pairs.clone() {
-                sled.get(key);
+                sled.get(key).unwrap();
             }
         });
     });
-
-    Ok(())
 }
 
 criterion_group!(benches, kvs_write, kvs_read, sled_write, sled_read);
@@ -0,0 +1,71 @@
+extern crate env_logger;
+extern crate log;
+extern crate structopt;
+
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsError, Result};
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+enum AdminCommands {
+    /// Rewrite the logs of a kvs store that are in an older on-disk format
+    #[structopt(name = "upgrade")]
+    Upgrade {
+        /// Directory of the store [default: the current directory]
+        #[structopt(long = "dir", parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// Only list the logs that would be rewritten
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+
+        /// Keys the store is encrypted with, as passed to kvs-server
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    match AdminCommands::from_args() {
+        AdminCommands::Upgrade {
+            dir,
+            dry_run,
+            key_file,
+        } => {
+            let dir = dir.unwrap_or_else(|| PathBuf::from("."));
+            // Keep the store's own settings, such as how much history it keeps
+            let mut options = KvStoreOptions::recorded(&dir)?;
+            if let Some(key_file) = &key_file {
+                let mut keys = EncryptionKey::read_key_file(key_file)?;
+                let key = keys.pop().ok_or_else(|| {
+                    KvsError::InvalidEncryptionKey(format!("no keys in {:?}", key_file))
+                })?;
+                options = options.encryption_key(key);
+                for key in keys {
+                    options = options.old_encryption_key(key);
+                }
+            }
+
+            let report = KvStore::upgrade(dir, options, dry_run)?;
+            if report.outdated.is_empty() {
+                println!("Already at format version {}", report.to_version);
+                return Ok(());
+            }
+            let verb = if dry_run { "Would rewrite" } else { "Rewrote" };
+            for (gen, version) in &report.outdated {
+                println!("{} generation {} (format version {})", verb, gen, version);
+            }
+            println!(
+                "{} from format version {} to {}",
+                if dry_run { "Would upgrade" } else { "Upgraded" },
+                report.from_version,
+                report.to_version
+            );
+        }
+    }
+
+    Ok(())
+}
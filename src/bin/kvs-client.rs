@@ -1,12 +1,12 @@
 extern crate env_logger;
-#[macro_use]
 extern crate log;
 extern crate serde_json;
 extern crate structopt;
 
+use kvs::protocol::{Request, Response};
 use kvs::{KvsCommands, Result};
 
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::TcpStream;
 use structopt::StructOpt;
 
@@ -17,6 +17,10 @@ struct KvsOptions {
 
     #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
     address: String,
+
+    /// Keyspace to use instead of the server's default one
+    #[structopt(long = "namespace")]
+    namespace: Option<String>,
 }
 
 fn main() -> Result<()> {
@@ -25,35 +29,35 @@ fn main() -> Result<()> {
 
     let stream = TcpStream::connect(opts.address)?;
 
-    let mut reader = BufReader::new(&stream);
+    let reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
 
-    match opts.command {
-        KvsCommands::Get { key } => {
-            serde_json::to_writer(&mut writer, &KvsCommands::Get { key })?;
-        }
-        KvsCommands::Set { key, value } => {
-            serde_json::to_writer(
-                &mut writer,
-                &KvsCommands::Set {
-                    key: key.clone(),
-                    value: value.clone(),
-                },
-            )?;
+    let request = match opts.namespace {
+        Some(namespace) => Request::Namespaced {
+            namespace,
+            request: Box::new(Request::from(opts.command)),
+        },
+        None => Request::from(opts.command),
+    };
+    serde_json::to_writer(&mut writer, &request)?;
+    writer.flush()?;
+
+    match serde_json::from_reader(reader)? {
+        Response::Value(Some(value)) => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(&value)?;
+            stdout.write_all(b"\n")?;
         }
-        KvsCommands::Remove { key } => {
-            serde_json::to_writer(&mut writer, &KvsCommands::Remove { key })?;
+        Response::Value(None) => println!("Key not found"),
+        Response::Ok | Response::Applied(true) => {}
+        Response::Applied(false) => {
+            eprintln!("Condition not met");
+            std::process::exit(1);
         }
-    }
-    writer.flush()?;
-    let mut buffer = String::new();
-    reader.read_to_string(&mut buffer)?;
-    if !buffer.is_empty() {
-        if buffer.starts_with("Server error:") {
-            eprintln!("{}", buffer);
+        Response::Err(message) => {
+            eprintln!("Server error: {}", message);
             std::process::exit(1);
-        } else {
-            println!("{}", buffer);
         }
     }
 
@@ -8,18 +8,23 @@ extern crate log;
 extern crate serde_json;
 extern crate structopt;
 
-use kvs::{KvStore, KvsCommands, KvsEngine, Result, SledKvsEngine};
+use kvs::protocol::{Request, Response};
+use kvs::{
+    Durability, EncryptionKey, KvStore, KvsEngine, KvsError, NaiveThreadPool, RayonThreadPool,
+    Result, SharedQueueThreadPool, SledKvsEngine, ThreadPool,
+};
 
 use env_logger::Builder;
 use log::LevelFilter;
 use std::env::{current_dir, var_os};
-use std::fs::read_to_string;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: EngineName = EngineName::kvs;
+const DEFAULT_POOL: PoolName = PoolName::shared;
 
 #[derive(StructOpt, Debug)]
 struct KvsOptions {
@@ -39,6 +44,47 @@ struct KvsOptions {
         raw(possible_values = "&EngineName::variants()")
     )]
     engine: Option<EngineName>,
+
+    #[structopt(
+        long,
+        help = "Number of threads serving client connections [default: number of CPUs]",
+        value_name = "N",
+        parse(try_from_str = "parse_threads")
+    )]
+    threads: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "Thread pool implementation",
+        value_name = "POOL-NAME",
+        raw(possible_values = "&PoolName::variants()")
+    )]
+    pool: Option<PoolName>,
+
+    #[structopt(
+        long,
+        help = "Keyspace to use for requests that don't name one [default: the default keyspace]",
+        value_name = "NAME"
+    )]
+    namespace: Option<String>,
+
+    #[structopt(
+        long,
+        help = "When writes are synced to disk: none, every-write, group-commit or \
+                periodic:<ms> [default: none]",
+        value_name = "POLICY"
+    )]
+    durability: Option<Durability>,
+
+    #[structopt(
+        long = "key-file",
+        help = "Encrypt data with the last key in this file, one <id>:<hex key> per line; \
+                earlier keys are only used to read data written before rotating keys \
+                (kvs engine only)",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
 }
 
 arg_enum! {
@@ -50,76 +96,111 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum PoolName {
+        naive,
+        shared,
+        rayon,
+    }
+}
+
 #[derive(new)]
-struct Server<E: KvsEngine> {
+struct Server<E: KvsEngine, P: ThreadPool> {
     engine: E,
+    pool: P,
 }
 
-impl<E: KvsEngine> Server<E> {
-    fn start(&mut self, address: &SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(address.clone())?;
+impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
+    fn start(&self, address: &SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(address)?;
         info!("listening on {}", address);
         debug!("Current directory is {:?}", current_dir()?);
 
         for stream in listener.incoming() {
-            self.handle_client(stream?)?;
+            let engine = self.engine.clone();
+            match stream {
+                Ok(stream) => self.pool.spawn(move || {
+                    if let Err(e) = handle_client(engine, stream) {
+                        error!("Error serving client: {}", e);
+                    }
+                }),
+                Err(e) => error!("Connection failed: {}", e),
+            }
         }
 
         Ok(())
     }
+}
 
-    fn handle_client(&mut self, stream: TcpStream) -> Result<()> {
-        debug!("Got connection: {:#?}", stream);
-
-        let reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-
-        let command_stream =
-            serde_json::Deserializer::from_reader(reader).into_iter::<KvsCommands>();
-
-        for command_result in command_stream {
-            let command = command_result?;
-            debug!("Got command: {:?}", command);
-            match command {
-                KvsCommands::Get { key } => {
-                    debug!("Get, key = {}", key);
-                    match self.engine.get(key) {
-                        Ok(response) => {
-                            match response {
-                                None => {
-                                    debug!("Got None");
-                                    writer.write(b"Key not found")?;
-                                }
-                                Some(value) => {
-                                    debug!("Got {}", value);
-                                    writer.write(value.as_bytes())?;
-                                }
-                            };
-                        }
-                        Err(e) => {
-                            writer.write(format!("Server error: {}", e).as_bytes())?;
-                        }
-                    };
-                }
-                KvsCommands::Set { key, value } => {
-                    if let Err(e) = self.engine.set(key, value) {
-                        writer.write(format!("Server error: {}", e).as_bytes())?;
-                    }
-                }
-                KvsCommands::Remove { key } => {
-                    if let Err(e) = self.engine.remove(key) {
-                        debug!("Got error: {}", e);
-                        writer.write(format!("Server error: {}", e).as_bytes())?;
-                    }
-                }
+fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    debug!("Got connection: {:#?}", stream);
+
+    let reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    let request_stream = serde_json::Deserializer::from_reader(reader).into_iter::<Request>();
+
+    for request_result in request_stream {
+        let request = request_result?;
+        debug!("Got request: {:?}", request);
+        let response = respond(&engine, request);
+        debug!("Sending response: {:?}", response);
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.flush()?;
+        stream.shutdown(Shutdown::Both)?;
+    }
+
+    debug!("Exiting command loop");
+
+    Ok(())
+}
+
+fn respond<E: KvsEngine>(engine: &E, request: Request) -> Response {
+    match request {
+        Request::Get { key } => match engine.get_bytes(&key) {
+            Ok(value) => Response::Value(value),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Set { key, value, ttl } => {
+            let result = match ttl {
+                Some(ttl) => engine.set_with_ttl_bytes(key, value, ttl),
+                None => engine.set_bytes(key, value),
+            };
+            match result {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Err(e.to_string()),
             }
-            writer.flush()?;
-            stream.shutdown(Shutdown::Both)?;
         }
+        Request::Remove { key } => match engine.remove_bytes(&key) {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::CompareAndSwap { key, expected, new } => {
+            applied(engine.compare_and_swap_bytes(key, expected, new))
+        }
+        Request::SetIfAbsent { key, value } => applied(engine.set_if_absent_bytes(key, value)),
+        Request::SetIfPresent { key, value } => applied(engine.set_if_present_bytes(key, value)),
+        Request::Namespaced { namespace, request } => match engine.open_tree(&namespace) {
+            Ok(tree) => respond(&tree, *request),
+            Err(e) => Response::Err(e.to_string()),
+        },
+    }
+}
 
-        debug!("Exiting command loop");
+fn applied(result: Result<bool>) -> Response {
+    match result {
+        Ok(applied) => Response::Applied(applied),
+        Err(e) => Response::Err(e.to_string()),
+    }
+}
 
-        Ok(())
+fn parse_threads(s: &str) -> std::result::Result<u32, String> {
+    match s.parse() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        Ok(threads) => Ok(threads),
+        Err(e) => Err(format!("{}", e)),
     }
 }
 
@@ -138,47 +219,64 @@ fn main() -> Result<()> {
     info!("kvs {}", crate_version!());
 
     let arg_engine = opts.engine.unwrap_or(DEFAULT_ENGINE);
-    debug!("Engine {} from command line args", arg_engine);
-    let current_engine = read_current_engine_name()?;
-    if let Some(current_engine_name) = current_engine {
-        if arg_engine != current_engine_name {
-            error!("Engine requested doesn't match engine last used");
-            std::process::exit(1);
-        }
-    }
-
     debug!("Using {} engine", arg_engine);
 
-    write_current_engine_name(arg_engine)?;
+    let durability = opts.durability.unwrap_or_default();
+    info!("Syncing writes with {} durability", durability);
 
     match arg_engine {
-        EngineName::kvs => Server::<KvStore>::new(KvStore::open(".")?).start(&opts.addr),
+        EngineName::kvs => {
+            let mut builder = KvStore::builder().durability(durability);
+            if let Some(key_file) = &opts.key_file {
+                let mut keys = EncryptionKey::read_key_file(key_file)?;
+                let key = keys.pop().ok_or_else(|| {
+                    KvsError::InvalidEncryptionKey(format!("no keys in {:?}", key_file))
+                })?;
+                info!("Encrypting data with key {}", key.id());
+                builder = builder.encryption_key(key);
+                for key in keys {
+                    builder = builder.old_encryption_key(key);
+                }
+            }
+            run(exit_on_mismatch(builder.open("."))?, &opts)
+        }
         EngineName::sled => {
-            Server::<SledKvsEngine>::new(SledKvsEngine::open(".")?).start(&opts.addr)
+            if opts.key_file.is_some() {
+                error!("The sled engine doesn't support encryption");
+                std::process::exit(1);
+            }
+            let engine = SledKvsEngine::open_with_durability(".", durability);
+            run(exit_on_mismatch(engine)?, &opts)
         }
-    }?;
-
-    Ok(())
+    }
 }
 
-fn write_current_engine_name(engine: EngineName) -> Result<()> {
-    let engine_file = current_dir()?.join("engine");
-    debug!("writing engine name '{}' to {:?}", engine, engine_file);
-    std::fs::write(engine_file, engine.to_string())?;
-    Ok(())
-}
+fn run<E: KvsEngine>(engine: E, opts: &KvsOptions) -> Result<()> {
+    let engine = match &opts.namespace {
+        Some(namespace) => {
+            info!("Serving keyspace {:?} by default", namespace);
+            engine.open_tree(namespace)?
+        }
+        None => engine,
+    };
+    let threads = opts.threads.unwrap_or_else(|| num_cpus::get() as u32);
+    let pool = opts.pool.unwrap_or(DEFAULT_POOL);
+    info!("Using {} thread pool with {} threads", pool, threads);
 
-fn read_current_engine_name() -> Result<Option<EngineName>> {
-    let engine_file = current_dir()?.join("engine");
-    if engine_file.exists() {
-        match read_to_string(engine_file)?.parse() {
-            Ok(engine) => Ok(Some(engine)),
-            Err(_) => {
-                warn!("Unparseable engine file");
-                Ok(None)
-            }
+    match pool {
+        PoolName::naive => Server::new(engine, NaiveThreadPool::new(threads)?).start(&opts.addr),
+        PoolName::shared => {
+            Server::new(engine, SharedQueueThreadPool::new(threads)?).start(&opts.addr)
         }
-    } else {
-        Ok(None)
+        PoolName::rayon => Server::new(engine, RayonThreadPool::new(threads)?).start(&opts.addr),
+    }
+}
+
+/// Exit if the store was created by another engine than the one asked for.
+fn exit_on_mismatch<E>(opened: Result<E>) -> Result<E> {
+    if let Err(e @ KvsError::EngineMismatch { .. }) = &opened {
+        error!("{}", e);
+        std::process::exit(1);
     }
+    opened
 }
@@ -0,0 +1,76 @@
+//! Compression of values in `KvStore` logs.
+
+use crate::{KvsError, Result};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// How `KvStore` compresses values it writes.
+///
+/// Each value is compressed on its own, and only kept compressed if that
+/// makes it smaller, so compressed and uncompressed records sit side by
+/// side in a log. Compressed values can be read whatever the setting, as
+/// long as kvs was built with support for them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store values as they are. The default.
+    #[default]
+    None,
+    /// LZ4 block compression. Needs the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            #[cfg(feature = "lz4")]
+            "lz4" => Ok(Compression::Lz4),
+            #[cfg(not(feature = "lz4"))]
+            "lz4" => Err("lz4 compression needs the lz4 feature".to_owned()),
+            _ => Err(format!("expected none or lz4, got {:?}", s)),
+        }
+    }
+}
+
+/// `value` compressed with `compression`, if that makes it smaller.
+#[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+pub(crate) fn compress(compression: Compression, value: &[u8]) -> Option<Vec<u8>> {
+    match compression {
+        Compression::None => None,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            let compressed = lz4_flex::compress_prepend_size(value);
+            if compressed.len() < value.len() {
+                Some(compressed)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// The original of a value `compress` produced.
+#[cfg(feature = "lz4")]
+pub(crate) fn decompress(value: &[u8]) -> Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(value).map_err(|_| KvsError::CorruptValue)
+}
+
+/// The original of a value `compress` produced.
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn decompress(_value: &[u8]) -> Result<Vec<u8>> {
+    Err(KvsError::CompressionUnsupported)
+}
@@ -0,0 +1,77 @@
+//! Advisory locking of a store directory.
+
+use crate::{KvsError, Result};
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+const LOCK_FILE: &str = "LOCK";
+const READ_LOCK_FILE: &str = "READ_LOCK";
+
+/// An advisory lock on a store directory, held until dropped.
+///
+/// A store opened for writing holds `LOCK` exclusively for as long as it is
+/// open, so that two writers never append to the same log.
+///
+/// `READ_LOCK` keeps readers away from logs a writer is recovering. The
+/// writer holds it exclusively only while it recovers the logs on open, and
+/// read-only stores share it whenever they read the logs, on open and on
+/// every refresh. Neither holds it for longer, so a writer can open, or be
+/// restarted, while read-only stores are following along; it only waits for
+/// them to finish what they are reading.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    /// Closing the file releases the lock.
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock the store at `path` exclusively, creating the lock file if need
+    /// be. Fails with `AlreadyLocked` rather than waiting if anyone else
+    /// holds the lock.
+    pub(crate) fn exclusive(path: &Path) -> Result<DirLock> {
+        let file = open_lock_file(path, LOCK_FILE)?;
+        match file.try_lock() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(e) => Err(lock_error(path, e)),
+        }
+    }
+
+    /// Keep read-only stores out of the logs of the store at `path` while
+    /// they are recovered, waiting for any reading them now to finish.
+    pub(crate) fn recovering(path: &Path) -> Result<DirLock> {
+        let file = open_lock_file(path, READ_LOCK_FILE)?;
+        file.lock()?;
+        Ok(DirLock { _file: file })
+    }
+
+    /// Share the read lock on the store at `path` without creating anything,
+    /// waiting for any recovery under way to finish. Returns `None` if no
+    /// writer has ever created the lock file.
+    pub(crate) fn reading(path: &Path) -> Result<Option<DirLock>> {
+        let file = match File::open(path.join(READ_LOCK_FILE)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        file.lock_shared()?;
+        Ok(Some(DirLock { _file: file }))
+    }
+}
+
+fn open_lock_file(path: &Path, name: &str) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(name))?)
+}
+
+fn lock_error(path: &Path, error: TryLockError) -> KvsError {
+    match error {
+        TryLockError::WouldBlock => KvsError::AlreadyLocked(path.to_owned()),
+        TryLockError::Error(e) => e.into(),
+    }
+}
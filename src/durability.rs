@@ -0,0 +1,103 @@
+//! When writes are forced out to disk.
+
+use crate::Result;
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How hard an engine works to make a write survive a power failure before
+/// acknowledging it.
+///
+/// `KvStore` always hands writes to the operating system before
+/// acknowledging them, so they survive the process crashing whatever the
+/// setting. sled buffers writes itself until it flushes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the operating system (or for sled, its own background
+    /// flusher) to write data out. The default, as it was before durability
+    /// could be chosen.
+    #[default]
+    None,
+    /// Sync every write to disk before acknowledging it.
+    EveryWrite,
+    /// Sync in the background at this interval, so a power failure loses at
+    /// most about that much of the most recent writes. Intervals under a
+    /// millisecond are treated as one.
+    Periodic(Duration),
+    /// Sync before acknowledging, but let writers that arrive while a sync
+    /// is under way share the next one instead of each syncing in turn.
+    GroupCommit,
+}
+
+/// Parses the names `Display` gives: `none`, `every-write`, `group-commit`
+/// and `periodic:<milliseconds>`, where the interval can't be zero.
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "every-write" => Ok(Durability::EveryWrite),
+            "group-commit" => Ok(Durability::GroupCommit),
+            _ => match s.strip_prefix("periodic:").map(str::parse) {
+                Some(Ok(millis)) if millis > 0 => {
+                    Ok(Durability::Periodic(Duration::from_millis(millis)))
+                }
+                _ => Err(format!(
+                    "expected none, every-write, group-commit or periodic:<ms> with ms above \
+                     zero, got {:?}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::None => write!(f, "none"),
+            Durability::EveryWrite => write!(f, "every-write"),
+            Durability::Periodic(interval) => write!(f, "periodic:{}", interval.as_millis()),
+            Durability::GroupCommit => write!(f, "group-commit"),
+        }
+    }
+}
+
+/// Lets writers that finish at about the same time share one sync.
+///
+/// Each writer notes its write once it has reached the operating system,
+/// then commits. Only one sync runs at a time, and it covers every write
+/// noted before it started, so writers queued up behind it usually find
+/// their writes already synced.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    /// Number of writes noted so far.
+    written: AtomicU64,
+    /// Number of writes known to be on disk. Held while syncing.
+    synced: Mutex<u64>,
+}
+
+impl GroupCommit {
+    /// Note that a write has reached the operating system.
+    pub(crate) fn wrote(&self) {
+        self.written.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Make sure every write noted so far is on disk, calling `sync` unless
+    /// a sync that started after them already has.
+    pub(crate) fn commit(&self, sync: impl FnOnce() -> Result<()>) -> Result<()> {
+        let target = self.written.load(Ordering::SeqCst);
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= target {
+            return Ok(());
+        }
+        let covered = self.written.load(Ordering::SeqCst);
+        sync()?;
+        *synced = covered;
+        Ok(())
+    }
+}
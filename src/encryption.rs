@@ -0,0 +1,196 @@
+//! Encryption of `KvStore` logs at rest.
+//!
+//! Records are sealed one at a time with XChaCha20-Poly1305 under a random
+//! nonce, so any record can still be read on its own, and one that has been
+//! tampered with fails to open. Each is bound to where it was written, by
+//! passing its generation, offset and key id as associated data, so that
+//! one copied or moved elsewhere fails to open too. Every log records the
+//! id of the key it was
+//! written with in its header, which lets a store read logs written with an
+//! older key while compaction rewrites them with the current one.
+
+use crate::{KvsError, Result};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Length of a key, in bytes.
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// A key to encrypt a `KvStore` with, and the id that logs written with it
+/// are labelled with.
+///
+/// Ids only need to tell apart the keys a store has been encrypted with.
+/// Zero is taken to mean a log isn't encrypted, so it can't be used.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    id: u32,
+    key: [u8; KEY_LEN],
+}
+
+impl EncryptionKey {
+    pub fn new(id: u32, key: [u8; KEY_LEN]) -> Result<EncryptionKey> {
+        if id == 0 {
+            return Err(KvsError::InvalidEncryptionKey(
+                "key id 0 is reserved for unencrypted logs".to_owned(),
+            ));
+        }
+        Ok(EncryptionKey { id, key })
+    }
+
+    /// Parse a key written as `<id>:<key>`, the key being 64 hex digits.
+    pub fn parse(s: &str) -> Result<EncryptionKey> {
+        let invalid = |reason: &str| KvsError::InvalidEncryptionKey(reason.to_owned());
+        let (id, hex) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| invalid("expected <id>:<64 hex digits>"))?;
+        let id = id.parse().map_err(|_| invalid("key id isn't a number"))?;
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(invalid("key must be 64 hex digits"));
+        }
+        let mut key = [0; KEY_LEN];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).unwrap();
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid("key isn't hex"))?;
+        }
+        EncryptionKey::new(id, key)
+    }
+
+    /// Read the keys in the file at `path`, one per line in the form
+    /// `parse` takes. Blank lines and lines starting with `#` are skipped.
+    ///
+    /// By convention the last key is the one to encrypt with, and any
+    /// before it are older keys still needed to read existing logs.
+    pub fn read_key_file(path: impl AsRef<Path>) -> Result<Vec<EncryptionKey>> {
+        fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(EncryptionKey::parse)
+            .collect()
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Shows the id only, to keep the key out of logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// Seals and opens records with one key.
+pub(crate) struct Cipher {
+    pub(crate) id: u32,
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn new(key: &EncryptionKey) -> Cipher {
+        Cipher {
+            id: key.id,
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key.key)),
+        }
+    }
+
+    /// `plaintext` encrypted under a fresh nonce, which goes in front, and
+    /// bound to `associated`, which must be given again to open it.
+    pub(crate) fn seal(&self, plaintext: &[u8], associated: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: associated,
+        };
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, payload)
+            .expect("message too long to encrypt");
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// The plaintext of a message `seal` produced, or `None` if it has been
+    /// tampered with, or was sealed with another key or associated data.
+    pub(crate) fn open(&self, sealed: &[u8], associated: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: associated,
+        };
+        self.aead.decrypt(XNonce::from_slice(nonce), payload).ok()
+    }
+
+    /// Associated data binding a record to offset `offset` in the log for
+    /// `gen`, and to this key.
+    pub(crate) fn record_position(&self, gen: u64, offset: u64) -> [u8; 20] {
+        let mut associated = [0; 20];
+        associated[0..8].copy_from_slice(&gen.to_le_bytes());
+        associated[8..16].copy_from_slice(&offset.to_le_bytes());
+        associated[16..20].copy_from_slice(&self.id.to_le_bytes());
+        associated
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cipher").field("id", &self.id).finish()
+    }
+}
+
+/// The keys a store can read logs with, and the one it writes them with.
+#[derive(Debug, Default)]
+pub(crate) struct Keyring {
+    current: Option<Arc<Cipher>>,
+    ciphers: HashMap<u32, Arc<Cipher>>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<&EncryptionKey>, old: &[EncryptionKey]) -> Keyring {
+        let mut keyring = Keyring::default();
+        for key in old.iter().chain(current) {
+            keyring.ciphers.insert(key.id, Arc::new(Cipher::new(key)));
+        }
+        keyring.current = current.map(|key| keyring.ciphers[&key.id].clone());
+        keyring
+    }
+
+    /// The cipher to write with, if the store is encrypted.
+    pub(crate) fn current(&self) -> Option<&Cipher> {
+        self.current.as_deref()
+    }
+
+    /// Id of the key to write with; zero if the store isn't encrypted.
+    pub(crate) fn current_id(&self) -> u32 {
+        self.current.as_ref().map_or(0, |cipher| cipher.id)
+    }
+
+    /// The cipher for logs labelled with key `id`; none for unencrypted
+    /// logs.
+    pub(crate) fn get(&self, id: u32) -> Result<Option<Arc<Cipher>>> {
+        if id == 0 {
+            return Ok(None);
+        }
+        match self.ciphers.get(&id) {
+            Some(cipher) => Ok(Some(cipher.clone())),
+            None => Err(KvsError::MissingEncryptionKey(id)),
+        }
+    }
+}
@@ -2,6 +2,7 @@ use failure::Fail;
 use sled;
 use std::convert::From;
 use std::io;
+use std::path::PathBuf;
 use std::string;
 
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -20,12 +21,105 @@ pub enum KvsError {
     #[fail(display = "{}", _0)]
     UTF8Error(#[cause] string::FromUtf8Error),
 
+    #[fail(display = "{}", _0)]
+    ThreadPoolBuild(#[cause] rayon::ThreadPoolBuildError),
+
+    /// A thread pool was asked for zero threads
+    #[fail(display = "A thread pool needs at least one thread")]
+    NoThreads,
+
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
 
     #[fail(display = "Unexpected command type found")]
     UnexpectedCommandType,
+
+    #[fail(display = "Log file header is truncated")]
+    InvalidLogHeader,
+
+    #[fail(
+        display = "Format version {} is newer than this version of kvs supports",
+        _0
+    )]
+    UnsupportedFormatVersion(u32),
+
+    /// A damaged record was found somewhere other than the end of the newest log
+    #[fail(display = "Corrupt record in generation {} at offset {}", gen, offset)]
+    CorruptLog { gen: u64, offset: u64 },
+
+    /// A record read for a `get` failed its checksum
+    #[fail(
+        display = "Checksum mismatch in generation {} at offset {}",
+        gen, offset
+    )]
+    ChecksumMismatch { gen: u64, offset: u64 },
+
+    /// Keyspace names can't be empty or start with `__`
+    #[fail(display = "Invalid keyspace name {:?}", _0)]
+    InvalidTreeName(String),
+
+    /// A compressed value doesn't decompress, despite passing its checksum
+    #[fail(display = "Compressed value is corrupt")]
+    CorruptValue,
+
+    /// The store holds compressed values, but kvs was built without the
+    /// feature needed to read them
+    #[fail(display = "Value is compressed, but this build of kvs can't decompress it")]
+    CompressionUnsupported,
+
+    /// A record is intact, but fails authentication against the key its log
+    /// is encrypted with
+    #[fail(
+        display = "Record in generation {} at offset {} has been tampered with",
+        gen, offset
+    )]
+    Tampered { gen: u64, offset: u64 },
+
+    /// A log is encrypted with a key that wasn't supplied
+    #[fail(display = "Log is encrypted with key {}, which wasn't supplied", _0)]
+    MissingEncryptionKey(u32),
+
+    #[fail(display = "Invalid encryption key: {}", _0)]
+    InvalidEncryptionKey(String),
+
+    /// Another `KvStore`, in this process or another, has the store open
+    /// for writing
+    #[fail(display = "Store at {:?} is already in use", _0)]
+    AlreadyLocked(PathBuf),
+
+    /// `MANIFEST` isn't valid
+    #[fail(display = "Store manifest is corrupt")]
+    CorruptManifest,
+
+    /// `METADATA` isn't valid
+    #[fail(display = "Store metadata is corrupt")]
+    CorruptMetadata,
+
+    /// The store was created by another engine
+    #[fail(
+        display = "Store was created by the {} engine, not {}",
+        found, expected
+    )]
+    EngineMismatch { expected: String, found: String },
+
+    /// The manifest lists a generation whose log is gone, and isn't the
+    /// newest, which may not have been created yet
+    #[fail(display = "Log for generation {} is missing", _0)]
+    MissingLog(u64),
+
+    /// Upgrading a store with the options given would compact away history
+    /// that the options it was last opened with keep
+    #[fail(
+        display = "Upgrading would keep less history than the store does: {}",
+        _0
+    )]
+    HistoryNotRetained(String),
+
+    /// A write was attempted through a store opened with
+    /// `KvStore::open_read_only`
+    #[fail(display = "Store is open read-only")]
+    ReadOnly,
 }
 
 impl From<io::Error> for KvsError {
@@ -51,3 +145,9 @@ impl From<string::FromUtf8Error> for KvsError {
         KvsError::UTF8Error(error)
     }
 }
+
+impl From<rayon::ThreadPoolBuildError> for KvsError {
+    fn from(error: rayon::ThreadPoolBuildError) -> Self {
+        KvsError::ThreadPoolBuild(error)
+    }
+}
@@ -0,0 +1,189 @@
+//! Hint files for fast startup.
+//!
+//! Compaction writes a `<gen>.hint` file next to each log it produces,
+//! listing every record in the log without its value. Rebuilding the index
+//! from a hint file avoids reading and decoding every value in the log.
+//!
+//! A hint file is the magic bytes `KVSH`, a little-endian `u32` version,
+//! the `u64` length of the log it describes and the `u32` id of the key
+//! the log is encrypted with, followed by one entry per record:
+//!
+//! ```text
+//! +----------+------------+------------+---------+---------------+
+//! | flags u8 | offset u64 | length u64 | seq u64 | timestamp u64 |
+//! +----------+------------+------------+---------+---------------+
+//! +----------------+--------------+-------------+------+-----+
+//! | expires_at u64 | tree_len u32 | key_len u32 | tree | key |
+//! +----------------+--------------+-------------+------+-----+
+//! ```
+//!
+//! and finally a CRC32 of everything before it. An `expires_at` of zero
+//! means the value never expires, and an empty tree is the default
+//! keyspace. If the log is encrypted, the entries are sealed together with
+//! the same key, as they hold its keys, and bound to the generation of the
+//! log and the rest of the header.
+//!
+//! A hint file that is missing, damaged, or doesn't match the length or
+//! key of its log is ignored.
+
+use crate::encryption::Cipher;
+use crate::Result;
+
+use crc32fast::Hasher;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"KVSH";
+const VERSION: u32 = 5;
+const HEADER_LEN: usize = 20;
+const ENTRY_HEADER_LEN: usize = 49;
+
+const FLAG_TOMBSTONE: u8 = 1;
+
+/// Location of one record in a log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HintEntry {
+    pub tree: String,
+    pub key: Vec<u8>,
+    pub offset: u64,
+    pub length: u64,
+    pub seq: u64,
+    pub timestamp: u64,
+    pub expires_at: Option<u64>,
+    pub tombstone: bool,
+}
+
+/// Write a hint file for the log for `gen`, of `log_len` bytes and
+/// encrypted with `cipher` if at all, to `path`.
+pub fn write(
+    path: &Path,
+    gen: u64,
+    log_len: u64,
+    entries: &[HintEntry],
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        buf.push(if entry.tombstone { FLAG_TOMBSTONE } else { 0 });
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        buf.extend_from_slice(&entry.length.to_le_bytes());
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&entry.timestamp.to_le_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&(entry.tree.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(entry.tree.as_bytes());
+        buf.extend_from_slice(&entry.key);
+    }
+    let mut header = Vec::with_capacity(HEADER_LEN + buf.len() + 4);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&log_len.to_le_bytes());
+    header.extend_from_slice(&cipher.map_or(0, |cipher| cipher.id).to_le_bytes());
+    if let Some(cipher) = cipher {
+        buf = cipher.seal(&buf, &associated_data(gen, &header));
+    }
+    header.extend_from_slice(&buf);
+    let mut buf = header;
+    let mut hasher = Hasher::new();
+    hasher.update(&buf);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+    let mut file = File::create(path)?;
+    file.write_all(&buf)?;
+    file.sync_all()
+}
+
+/// Read the hint file at `path`, if there is a usable one for the log for
+/// `gen`, of `log_len` bytes and encrypted with `cipher` if at all.
+pub fn read(
+    path: &Path,
+    gen: u64,
+    log_len: u64,
+    cipher: Option<&Cipher>,
+) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match parse(&buf, gen, log_len, cipher) {
+        Some(entries) => Ok(Some(entries)),
+        None => {
+            warn!("Ignoring unusable hint file {:?}", path);
+            Ok(None)
+        }
+    }
+}
+
+/// Associated data binding the entries of a hint file to the log for `gen`
+/// and to `header`.
+fn associated_data(gen: u64, header: &[u8]) -> Vec<u8> {
+    let mut associated = gen.to_le_bytes().to_vec();
+    associated.extend_from_slice(header);
+    associated
+}
+
+fn parse(buf: &[u8], gen: u64, log_len: u64, cipher: Option<&Cipher>) -> Option<Vec<HintEntry>> {
+    if buf.len() < HEADER_LEN + 4 {
+        return None;
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    let mut hasher = Hasher::new();
+    hasher.update(body);
+    if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap())
+        || &body[0..4] != MAGIC
+        || u32::from_le_bytes(body[4..8].try_into().unwrap()) != VERSION
+        || u64::from_le_bytes(body[8..16].try_into().unwrap()) != log_len
+        || u32::from_le_bytes(body[16..20].try_into().unwrap()) != cipher.map_or(0, |c| c.id)
+    {
+        return None;
+    }
+    let opened;
+    let mut rest = match cipher {
+        Some(cipher) => {
+            let associated = associated_data(gen, &body[..HEADER_LEN]);
+            opened = cipher.open(&body[HEADER_LEN..], &associated)?;
+            &opened[..]
+        }
+        None => &body[HEADER_LEN..],
+    };
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let tombstone = rest[0] & FLAG_TOMBSTONE != 0;
+        let offset = u64::from_le_bytes(rest[1..9].try_into().unwrap());
+        let length = u64::from_le_bytes(rest[9..17].try_into().unwrap());
+        let seq = u64::from_le_bytes(rest[17..25].try_into().unwrap());
+        let timestamp = u64::from_le_bytes(rest[25..33].try_into().unwrap());
+        let expires_at = match u64::from_le_bytes(rest[33..41].try_into().unwrap()) {
+            0 => None,
+            expires_at => Some(expires_at),
+        };
+        let tree_len = u32::from_le_bytes(rest[41..45].try_into().unwrap()) as usize;
+        let key_len = u32::from_le_bytes(rest[45..49].try_into().unwrap()) as usize;
+        rest = &rest[ENTRY_HEADER_LEN..];
+        if rest.len() < tree_len + key_len {
+            return None;
+        }
+        let tree = String::from_utf8(rest[..tree_len].to_vec()).ok()?;
+        let key = rest[tree_len..tree_len + key_len].to_vec();
+        rest = &rest[tree_len + key_len..];
+        entries.push(HintEntry {
+            tree,
+            key,
+            offset,
+            length,
+            seq,
+            timestamp,
+            expires_at,
+            tombstone,
+        });
+    }
+    Some(entries)
+}
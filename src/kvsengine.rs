@@ -1,7 +1,279 @@
-use crate::Result;
+use crate::{KvsError, Result, WriteBatch};
 
-pub trait KvsEngine {
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn remove(&mut self, key: String) -> Result<()>;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Iterator over key/value pairs, in key order.
+pub type KvsIterator = Box<dyn Iterator<Item = Result<(String, String)>>>;
+
+/// Iterator over raw key/value pairs, in key order.
+pub type KvsBytesIterator = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// A key/value storage engine.
+///
+/// Engines are cloned into every thread that serves requests, so all methods
+/// take `&self` and every clone must refer to the same underlying store.
+///
+/// A store is divided into keyspaces, each an independent set of keys. The
+/// handle an engine is opened with is on the default keyspace, and
+/// `open_tree` gives handles on named ones.
+///
+/// Keys and values are arbitrary bytes. The `String` methods are
+/// conveniences on top of the byte methods, and fail with
+/// `KvsError::UTF8Error` if they come across data that isn't UTF-8.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only view of the engine at a single point in time.
+    type Snapshot: KvsSnapshot;
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Set `key` to `value`, and have it expire after `ttl`.
+    ///
+    /// Expired keys behave as if they had been removed. A plain `set`
+    /// replaces the value with one that never expires.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Make an existing key expire after `ttl`, replacing any earlier expiry.
+    fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()>;
+
+    /// Time left before `key` expires, or `None` if it never does.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>>;
+
+    /// Apply every operation in `batch`, or none of them if the process dies
+    /// part way through.
+    ///
+    /// Reads never see part of a batch: one that sees any of its operations
+    /// sees all of them.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Atomically replace the value of `key` with `new`, if it is currently
+    /// `expected`, returning whether it was replaced.
+    ///
+    /// `None` stands for the key not existing: an `expected` of `None` only
+    /// matches a missing key, and a `new` of `None` removes the key.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Set `key` to `value` only if it doesn't exist, returning whether it
+    /// was set.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Set `key` to `value` only if it already exists, returning whether it
+    /// was set.
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        loop {
+            let current = match self.get_bytes(&key)? {
+                Some(current) => current,
+                None => return Ok(false),
+            };
+            if self.compare_and_swap_bytes(key.clone(), Some(current), Some(value.clone()))? {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Iterate over the keys in `range`, in order.
+    ///
+    /// The iterator is not a snapshot: each step looks up the next key after
+    /// the last one returned, so writes made while iterating may or may not
+    /// be seen.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator>;
+
+    /// Iterate over the keys starting with `prefix`, in order.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        let iter = self.scan_bytes((Bound::Included(prefix.clone()), Bound::Unbounded))?;
+        Ok(take_prefix(iter, prefix))
+    }
+
+    /// Capture the current contents of the engine. Writes made afterwards
+    /// aren't seen by the snapshot.
+    ///
+    /// `KvStore` copies the keyspace's index and keeps the logs it points
+    /// into, but `SledKvsEngine` has to copy every key and value, holding up
+    /// writes while it does.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Handle on the keyspace called `name`, in the same store as `self`.
+    ///
+    /// A keyspace comes into being when something is first written to it.
+    /// Names can't be empty or start with `__`, which is kept for the
+    /// engines' own use.
+    fn open_tree(&self, name: &str) -> Result<Self>;
+
+    /// Names of the keyspaces other than the default one, in order.
+    fn tree_names(&self) -> Result<Vec<String>>;
+
+    /// Remove the keyspace called `name` and everything in it, returning
+    /// whether it existed.
+    ///
+    /// Handles on the keyspace stay usable, and see it empty.
+    fn drop_tree(&self, name: &str) -> Result<bool>;
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        self.expire_bytes(key.as_bytes(), ttl)
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_bytes())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// `String` version of `scan_bytes`.
+    ///
+    /// UTF-8 sorts in the same order as the code points it encodes, so keys
+    /// come back in the same order as the `String`s they decode to.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvsIterator> {
+        let (start, end) = owned_bounds(&range);
+        Ok(to_strings(self.scan_bytes((
+            start.map(String::into_bytes),
+            end.map(String::into_bytes),
+        ))?))
+    }
+
+    /// `String` version of `scan_prefix_bytes`.
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIterator> {
+        Ok(to_strings(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
+}
+
+/// A read-only view of an engine, as it was when the snapshot was taken.
+///
+/// Values that expire after the snapshot was taken are still visible in it.
+pub trait KvsSnapshot: Clone + Send + 'static {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Iterate over the keys in `range`, in order.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator>;
+
+    /// Iterate over the keys starting with `prefix`, in order.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<KvsBytesIterator> {
+        let iter = self.scan_bytes((Bound::Included(prefix.clone()), Bound::Unbounded))?;
+        Ok(take_prefix(iter, prefix))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// `String` version of `scan_bytes`.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvsIterator> {
+        let (start, end) = owned_bounds(&range);
+        Ok(to_strings(self.scan_bytes((
+            start.map(String::into_bytes),
+            end.map(String::into_bytes),
+        ))?))
+    }
+
+    /// `String` version of `scan_prefix_bytes`.
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIterator> {
+        Ok(to_strings(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
+}
+
+fn take_prefix(iter: KvsBytesIterator, prefix: Vec<u8>) -> KvsBytesIterator {
+    Box::new(iter.take_while(move |item| match item {
+        Ok((key, _)) => key.starts_with(&prefix),
+        Err(_) => true,
+    }))
+}
+
+fn to_strings(iter: KvsBytesIterator) -> KvsIterator {
+    Box::new(iter.map(|item| {
+        let (key, value) = item?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
+}
+
+/// Check that `name` can be used for a keyspace.
+pub(crate) fn check_tree_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with("__") {
+        return Err(KvsError::InvalidTreeName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Milliseconds since the Unix epoch, the unit expiry times are kept in.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Expiry time for a value set now with a time to live of `ttl`.
+pub(crate) fn expiry_time(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Time left until `expires_at`.
+pub(crate) fn time_left(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
+
+/// Owned copy of the bounds of `range`.
+pub(crate) fn owned_bounds<K: Clone, R: RangeBounds<K>>(range: &R) -> (Bound<K>, Bound<K>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// Whether `(start, end)` can't contain any keys.
+///
+/// `BTreeMap::range` panics on such bounds, so they're checked up front.
+pub(crate) fn is_empty_range<K: Ord>(start: &Bound<K>, end: &Bound<K>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
 }
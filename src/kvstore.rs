@@ -1,27 +1,424 @@
-use crate::KvsEngine;
-use crate::{KvsCommands, KvsError, Result};
+use crate::dirlock::DirLock;
+use crate::durability::GroupCommit;
+use crate::encryption::{Cipher, Keyring};
+use crate::hintfile::{self, HintEntry};
+use crate::kvsengine::{check_tree_name, expiry_time, is_empty_range, now_millis};
+use crate::kvsengine::{owned_bounds, time_left};
+use crate::logformat::{self, DecodeError, Header, LogFormat, Record, BATCH_HEADER_LEN};
+use crate::manifest::{self, Manifest, MANIFEST_FILE};
+use crate::metadata::{StoreMetadata, METADATA_FILE};
+use crate::valuecache::{CacheStats, ValueCache};
+use crate::{Durability, KvStoreOptions, KvsBytesIterator, KvsEngine, KvsSnapshot};
+use crate::{KvsCommands, KvsError, Result, WriteBatch};
+use memmap2::Mmap;
 use serde_json;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-#[derive(Debug)]
+/// Name `KvStore` records in the metadata of the stores it creates.
+const ENGINE_NAME: &str = "kvs";
+
+/// Log-structured key/value store.
+///
+/// A `KvStore` is cheap to clone, and every clone shares the same in-memory
+/// index and the same single writer. Each clone keeps its own set of file
+/// readers, so clones handed to different threads can serve `get`s in
+/// parallel while one thread at a time appends to the active log file.
+///
+/// All keyspaces share the same log, with every record naming the keyspace
+/// it belongs to.
+#[derive(Clone, Debug)]
 pub struct KvStore {
-    store: HashMap<String, FileLocation>,
-    writer: KvWriter<File>,
-    readers: HashMap<u64, BufReader<File>>,
-    gen: u64,
-    compactible: u64,
-    path: PathBuf,
+    /// Keyspace this handle reads and writes; empty for the default one.
+    tree: String,
+    store: Arc<RwLock<Index>>,
+    reader: KvReader,
+    pins: Arc<Mutex<Pins>>,
+    access: Access,
+}
+
+/// Whether a `KvStore` writes to its logs or only reads them.
+#[derive(Clone, Debug)]
+enum Access {
+    ReadWrite {
+        writer: Arc<Mutex<KvStoreWriter>>,
+        syncer: Arc<Syncer>,
+    },
+    ReadOnly(Arc<Mutex<Follower>>),
+}
+
+/// Index from every live key to the record holding its value, in key order.
+type KeyDir = BTreeMap<Vec<u8>, FileLocation>;
+
+/// Everything a `KvStore` keeps in memory about its log.
+#[derive(Debug, Default)]
+struct Index {
+    /// Every keyspace with keys or history in it, by name. The default
+    /// keyspace is the empty name.
+    trees: BTreeMap<String, Tree>,
+    /// Sequence number of the most recent record.
+    last_seq: u64,
+}
+
+/// The index of a single keyspace.
+#[derive(Debug, Default)]
+struct Tree {
+    keys: KeyDir,
+    /// Earlier versions kept by the retention policy, oldest first. The
+    /// history of a key that has been removed ends with the removal.
+    history: HashMap<Vec<u8>, Vec<Version>>,
+}
+
+/// A past version of a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Version {
+    location: FileLocation,
+    /// Whether this version is the key being removed.
+    removed: bool,
 }
 
-#[derive(Clone, Debug, new)]
+/// A version of a key, as returned by `KvStore::history`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyVersion {
+    pub seq: u64,
+    /// The value, or `None` if the key was removed or has expired.
+    pub value: Option<Vec<u8>>,
+}
+
+/// What `KvStore::upgrade` rewrote, or would rewrite in a dry run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeReport {
+    /// Format version of the oldest log before the upgrade.
+    pub from_version: u32,
+    /// Format version every log is in after the upgrade.
+    pub to_version: u32,
+    /// Generations whose logs were in an older format, and their versions.
+    pub outdated: Vec<(u64, u32)>,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, new)]
 pub struct FileLocation {
     gen: u64,
     offset: u64,
     length: u64,
+    expires_at: Option<u64>,
+    seq: u64,
+    timestamp: u64,
+}
+
+impl FileLocation {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl Index {
+    /// Apply `record`, read from `frame` in `gen` at `offset`, returning the
+    /// number of bytes of log it made stale.
+    ///
+    /// Lengths are taken from the frame rather than worked out from the
+    /// record, as older formats encode the same record differently.
+    fn apply(
+        &mut self,
+        record: Record,
+        gen: u64,
+        offset: u64,
+        frame: &[u8],
+        now: u64,
+        options: &KvStoreOptions,
+    ) -> u64 {
+        let length = frame.len() as u64;
+        match record {
+            Record::Set {
+                tree,
+                key,
+                expires_at,
+                seq,
+                timestamp,
+                ..
+            } => {
+                let location = FileLocation::new(gen, offset, length, expires_at, seq, timestamp);
+                self.set(tree, key, location, now, options)
+            }
+            Record::Remove {
+                tree,
+                key,
+                seq,
+                timestamp,
+            } => {
+                let location = FileLocation::new(gen, offset, length, None, seq, timestamp);
+                self.remove(&tree, key, location, now, options)
+            }
+            Record::Batch(records) => {
+                // The records inside a batch are indexed individually
+                let mut stale = BATCH_HEADER_LEN;
+                let mut inner_offset = offset + BATCH_HEADER_LEN;
+                for (record, inner) in records.into_iter().zip(logformat::batch_frames(frame)) {
+                    stale += self.apply(record, gen, inner_offset, inner, now, options);
+                    inner_offset += inner.len() as u64;
+                }
+                // As is the frame ending an encrypted batch
+                stale + offset + frame.len() as u64 - inner_offset
+            }
+            Record::DropTree {
+                tree,
+                seq,
+                timestamp,
+            } => {
+                let mut location = FileLocation::new(gen, offset, length, None, seq, timestamp);
+                self.sequence(&mut location);
+                // Everything in the keyspace goes, along with the record itself
+                let dropped = self.trees.remove(&tree);
+                location.length + dropped.map_or(0, |tree| tree.len())
+            }
+        }
+    }
+
+    /// Make the value at `location` the current version of `key` in `tree`.
+    fn set(
+        &mut self,
+        tree: String,
+        key: Vec<u8>,
+        mut location: FileLocation,
+        now: u64,
+        options: &KvStoreOptions,
+    ) -> u64 {
+        self.sequence(&mut location);
+        let keyspace = self.trees.entry(tree.clone()).or_default();
+        let stale = keyspace.set(key, location, now, options);
+        self.remove_if_empty(&tree);
+        stale
+    }
+
+    /// Record `key` being removed from `tree` by the record at `location`.
+    fn remove(
+        &mut self,
+        tree: &str,
+        key: Vec<u8>,
+        mut location: FileLocation,
+        now: u64,
+        options: &KvStoreOptions,
+    ) -> u64 {
+        self.sequence(&mut location);
+        let stale = match self.trees.get_mut(tree) {
+            Some(keyspace) => keyspace.remove(key, location, now, options),
+            None => location.length,
+        };
+        self.remove_if_empty(tree);
+        stale
+    }
+
+    /// Forget `tree` once there is nothing left in it.
+    fn remove_if_empty(&mut self, tree: &str) {
+        if self.trees.get(tree).is_some_and(Tree::is_empty) {
+            self.trees.remove(tree);
+        }
+    }
+
+    /// Give records from before sequence numbers were stored one, in the
+    /// order they are replayed.
+    fn sequence(&mut self, location: &mut FileLocation) {
+        if location.seq == 0 {
+            location.seq = self.last_seq + 1;
+        }
+        self.last_seq = self.last_seq.max(location.seq);
+    }
+
+    /// Current locations of the keys that `record` overwrites, removes or
+    /// drops.
+    fn superseded(&self, record: &Record) -> Vec<&FileLocation> {
+        match record {
+            Record::Set { tree, key, .. } | Record::Remove { tree, key, .. } => {
+                self.get(tree, key).into_iter().collect()
+            }
+            Record::Batch(records) => records
+                .iter()
+                .flat_map(|record| self.superseded(record))
+                .collect(),
+            Record::DropTree { tree, .. } => self
+                .trees
+                .get(tree)
+                .map_or_else(Vec::new, |tree| tree.keys.values().collect()),
+        }
+    }
+
+    /// Current location of `key` in `tree`.
+    fn get(&self, tree: &str, key: &[u8]) -> Option<&FileLocation> {
+        self.trees.get(tree)?.keys.get(key)
+    }
+
+    /// Every version of `key` in `tree` still known, oldest first, with
+    /// whether it is a removal.
+    fn versions(&self, tree: &str, key: &[u8]) -> Vec<(&FileLocation, bool)> {
+        self.trees
+            .get(tree)
+            .map_or_else(Vec::new, |tree| tree.versions(key))
+    }
+
+    /// Oldest generation that anything in the index refers to.
+    fn oldest_gen(&self) -> Option<u64> {
+        self.trees
+            .values()
+            .flat_map(|tree| {
+                let history = tree.history.values().flatten();
+                let history = history.map(|version| version.location.gen);
+                tree.keys
+                    .values()
+                    .map(|location| location.gen)
+                    .chain(history)
+            })
+            .min()
+    }
+}
+
+impl Tree {
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.history.is_empty()
+    }
+
+    /// Total length of the records the keyspace refers to.
+    fn len(&self) -> u64 {
+        let history = self.history.values().flatten();
+        let history = history.map(|version| version.location.length);
+        self.keys
+            .values()
+            .map(|location| location.length)
+            .chain(history)
+            .sum()
+    }
+
+    /// Make the value at `location` the current version of `key`.
+    fn set(
+        &mut self,
+        key: Vec<u8>,
+        location: FileLocation,
+        now: u64,
+        options: &KvStoreOptions,
+    ) -> u64 {
+        if location.is_expired(now) {
+            let old_location = self.keys.remove(&key);
+            return location.length + self.retire(key, old_location, now, options);
+        }
+        let old_location = self.keys.insert(key.clone(), location);
+        self.retire(key, old_location, now, options)
+    }
+
+    /// Record `key` being removed by the record at `location`.
+    fn remove(
+        &mut self,
+        key: Vec<u8>,
+        location: FileLocation,
+        now: u64,
+        options: &KvStoreOptions,
+    ) -> u64 {
+        let old_location = match self.keys.remove(&key) {
+            Some(old_location) => old_location,
+            None => return location.length,
+        };
+        let stale = self.retire(key.clone(), Some(old_location), now, options);
+        if !options.keeps_history() {
+            return stale + location.length;
+        }
+        let removal = Version {
+            location,
+            removed: true,
+        };
+        self.history.entry(key.clone()).or_default().push(removal);
+        stale + self.prune(&key, now, options)
+    }
+
+    /// Move a replaced version of `key` into its history, returning the
+    /// number of bytes the retention policy let go of.
+    fn retire(
+        &mut self,
+        key: Vec<u8>,
+        old_location: Option<FileLocation>,
+        now: u64,
+        options: &KvStoreOptions,
+    ) -> u64 {
+        if !options.keeps_history() {
+            return old_location.map_or(0, |location| location.length);
+        }
+        if let Some(location) = old_location {
+            let version = Version {
+                location,
+                removed: false,
+            };
+            self.history.entry(key.clone()).or_default().push(version);
+        }
+        self.prune(&key, now, options)
+    }
+
+    /// Drop the versions of `key` that are no longer retained, returning
+    /// their total length.
+    fn prune(&mut self, key: &[u8], now: u64, options: &KvStoreOptions) -> u64 {
+        let versions = match self.history.remove(key) {
+            Some(versions) => versions,
+            None => return 0,
+        };
+        let retained = retained(&versions, self.keys.get(key), now, options);
+        let mut stale = 0;
+        let mut kept = Vec::new();
+        for (version, retained) in versions.into_iter().zip(retained) {
+            // A removal with nothing older before it says nothing useful
+            if retained && (!kept.is_empty() || !version.removed) {
+                kept.push(version);
+            } else {
+                stale += version.location.length;
+            }
+        }
+        if !kept.is_empty() {
+            self.history.insert(key.to_vec(), kept);
+        }
+        stale
+    }
+
+    /// Every version of `key` still known, oldest first, with whether it is
+    /// a removal.
+    fn versions(&self, key: &[u8]) -> Vec<(&FileLocation, bool)> {
+        let history = self.history.get(key).into_iter().flatten();
+        history
+            .map(|version| (&version.location, version.removed))
+            .chain(self.keys.get(key).map(|location| (location, false)))
+            .collect()
+    }
+}
+
+/// Which of `versions`, followed by `current`, the retention policy keeps.
+fn retained(
+    versions: &[Version],
+    current: Option<&FileLocation>,
+    now: u64,
+    options: &KvStoreOptions,
+) -> Vec<bool> {
+    let newer_than_history = usize::from(current.is_some());
+    versions
+        .iter()
+        .enumerate()
+        .map(|(i, version)| {
+            let next = versions.get(i + 1).map(|next| &next.location);
+            match next.or(current) {
+                // The removal that is the key's latest state
+                None => true,
+                Some(next) => {
+                    let newer = versions.len() - 1 - i + newer_than_history;
+                    !version.location.is_expired(now) && options.retains(newer, next.timestamp, now)
+                }
+            }
+        })
+        .collect()
 }
 
 #[derive(Debug)]
@@ -46,7 +443,7 @@ impl<W: Write + Seek> Write for KvWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
         self.offset += len as u64;
-        Ok(buf.len())
+        Ok(len)
     }
 
     #[logfn(Trace)]
@@ -55,83 +452,1156 @@ impl<W: Write + Seek> Write for KvWriter<W> {
     }
 }
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Reads records out of the log files.
+///
+/// Generations older than `active` are never written to again, and are
+/// memory-mapped on first use. The maps are shared between clones, so
+/// reading from them is a slice lookup that needs no file cursor.
+///
+/// The active generation is still growing, so it is read through a file
+/// reader instead. Those are opened lazily and never shared between
+/// clones.
+///
+/// `safe_point` is the oldest generation still on disk; after a compaction
+/// bumps it, readers and maps for older generations are dropped.
+///
+/// Values read are kept in a cache shared by every clone and snapshot.
+#[derive(Debug)]
+struct KvReader {
+    path: Arc<PathBuf>,
+    keys: Arc<Keyring>,
+    safe_point: Arc<AtomicU64>,
+    /// Generation being appended to.
+    active: Arc<AtomicU64>,
+    maps: Arc<RwLock<HashMap<u64, Arc<MappedGen>>>>,
+    cache: Arc<ValueCache>,
+    readers: RefCell<HashMap<u64, GenReader>>,
+}
+
+/// An open log file along with its header, and the cipher to read it with
+/// if it is encrypted.
+#[derive(Debug)]
+struct GenReader {
+    header: Header,
+    cipher: Option<Arc<Cipher>>,
+    reader: BufReader<File>,
+}
+
+impl GenReader {
+    fn open(path: &Path, gen: u64, keys: &Keyring) -> Result<GenReader> {
+        let mut reader = BufReader::new(open_gen_file(path, gen)?);
+        let header = logformat::read_header(&mut reader)?;
+        let cipher = keys.get(header.key_id)?;
+        Ok(GenReader {
+            header,
+            cipher,
+            reader,
+        })
+    }
+}
+
+/// A log file that is no longer written to, mapped into memory.
+#[derive(Debug)]
+struct MappedGen {
+    header: Header,
+    cipher: Option<Arc<Cipher>>,
+    map: Mmap,
+}
+
+impl MappedGen {
+    fn open(path: &Path, gen: u64, keys: &Keyring) -> Result<MappedGen> {
+        let file = open_gen_file(path, gen)?;
+        // Only generations writes have moved past are mapped, and nothing
+        // changes those again; compaction replaces them with new files
+        let map = unsafe { Mmap::map(&file)? };
+        let header = logformat::read_header(&mut &map[..])?;
+        let cipher = keys.get(header.key_id)?;
+        Ok(MappedGen {
+            header,
+            cipher,
+            map,
+        })
+    }
+}
+
+impl Clone for KvReader {
+    fn clone(&self) -> Self {
+        KvReader {
+            path: self.path.clone(),
+            keys: self.keys.clone(),
+            safe_point: self.safe_point.clone(),
+            active: self.active.clone(),
+            maps: self.maps.clone(),
+            cache: self.cache.clone(),
+            readers: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl KvReader {
+    /// Drop this clone's readers for generations that have been compacted
+    /// away, or that are now read through a map.
+    fn close_stale_readers(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let active = self.active.load(Ordering::SeqCst);
+        self.readers
+            .borrow_mut()
+            .retain(|gen, _| *gen >= safe_point && *gen >= active);
+    }
+
+    /// Drop the maps and cached values of generations that have been
+    /// compacted away, for all clones. Reads already under way keep their
+    /// maps until they finish.
+    fn unmap_stale(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.maps
+            .write()
+            .unwrap()
+            .retain(|gen, _| *gen >= safe_point);
+        self.cache.retain_from(safe_point);
+    }
+
+    fn read_record(&self, location: &FileLocation) -> Result<Record> {
+        if location.gen < self.active.load(Ordering::SeqCst) {
+            let mapped = self.mapped(location.gen)?;
+            let start = location.offset as usize;
+            let frame = mapped
+                .map
+                .get(start..start + location.length as usize)
+                .ok_or(KvsError::CorruptLog {
+                    gen: location.gen,
+                    offset: location.offset,
+                })?;
+            return decode_record(&mapped.header, mapped.cipher.as_deref(), frame, location);
+        }
+
+        self.close_stale_readers();
+        let mut readers = self.readers.borrow_mut();
+        let gen_reader = match readers.entry(location.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(GenReader::open(&self.path, location.gen, &self.keys)?)
+            }
+        };
+        let reader = &mut gen_reader.reader;
+        reader.seek(SeekFrom::Start(location.offset))?;
+        let mut frame = vec![0; location.length as usize];
+        reader.read_exact(&mut frame)?;
+        decode_record(
+            &gen_reader.header,
+            gen_reader.cipher.as_deref(),
+            &frame,
+            location,
+        )
+    }
+
+    /// The map of finished generation `gen`, mapping it if no clone has yet.
+    fn mapped(&self, gen: u64) -> Result<Arc<MappedGen>> {
+        if let Some(mapped) = self.maps.read().unwrap().get(&gen) {
+            return Ok(mapped.clone());
+        }
+        match self.maps.write().unwrap().entry(gen) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let mapped = Arc::new(MappedGen::open(&self.path, gen, &self.keys)?);
+                Ok(entry.insert(mapped).clone())
+            }
+        }
+    }
+
+    fn read_value(&self, location: &FileLocation) -> Result<Vec<u8>> {
+        if let Some(value) = self.cache.get(location.gen, location.offset) {
+            return Ok(value);
+        }
+        let value = self.read_record(location)?.into_value()?;
+        self.cache.insert(location.gen, location.offset, &value);
+        Ok(value)
+    }
+}
+
+/// The single writer shared by all clones of a `KvStore`.
+#[derive(Debug)]
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    store: Arc<RwLock<Index>>,
+    keys: Arc<Keyring>,
+    writer: KvWriter<File>,
+    options: KvStoreOptions,
+    gen: u64,
+    compactible: u64,
+    /// Size of all generations on disk, live or not.
+    total: u64,
+    compactor: Compactor,
+    compaction: Option<JoinHandle<()>>,
+    syncer: Arc<Syncer>,
+    /// Held for as long as any clone of the store is open.
+    _lock: DirLock,
+}
+
+/// Gets appends to the active log onto disk, as the store's `Durability`
+/// setting asks.
+#[derive(Debug)]
+struct Syncer {
+    durability: Durability,
+    /// The active log, replaced whenever writes move on to a new one.
+    file: Mutex<File>,
+    group: GroupCommit,
+}
+
+impl Syncer {
+    /// Sync every write made so far, sharing the sync with any other
+    /// writers waiting on it.
+    fn sync(&self) -> Result<()> {
+        self.group
+            .commit(|| Ok(self.file.lock().unwrap().sync_data()?))
+    }
+
+    /// Sync in the background every `interval`, but at most once a
+    /// millisecond, until the store is dropped.
+    fn sync_periodically(syncer: Weak<Syncer>, interval: Duration) {
+        let interval = interval.max(Duration::from_millis(1));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let syncer = match syncer.upgrade() {
+                Some(syncer) => syncer,
+                None => return,
+            };
+            if let Err(e) = syncer.sync() {
+                error!("Periodic sync failed: {}", e);
+            }
+        });
+    }
+}
+
+/// How far a read-only store has read the logs a writer elsewhere may still
+/// be appending to.
+#[derive(Debug)]
+struct Follower {
+    path: Arc<PathBuf>,
+    keys: Arc<Keyring>,
+    options: KvStoreOptions,
+    /// Every generation loaded so far, and the offset loading stopped at.
+    loaded: BTreeMap<u64, u64>,
+}
+
+impl Follower {
+    /// Index whatever of `gen_list` hasn't been loaded yet.
+    fn load(&mut self, gen_list: &[u64], store: &mut Index) -> Result<()> {
+        for gen in gen_list {
+            self.load_gen(*gen, Some(gen) == gen_list.last(), store)?;
+        }
+        Ok(())
+    }
+
+    /// Index whatever of generation `gen` hasn't been loaded yet.
+    ///
+    /// The newest generation may end partway through a record, or even its
+    /// header, that the writer is still appending. That much is left for
+    /// the next refresh, rather than truncated.
+    fn load_gen(&mut self, gen: u64, newest: bool, store: &mut Index) -> Result<()> {
+        let from = self.loaded.get(&gen).copied();
+        let mut gen_reader = match GenReader::open(&self.path, gen, &self.keys) {
+            Err(KvsError::InvalidLogHeader) if newest => {
+                self.loaded.insert(gen, 0);
+                return Ok(());
+            }
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                if newest {
+                    return Ok(());
+                }
+                return Err(KvsError::MissingLog(gen));
+            }
+            result => result?,
+        };
+        let header_len = gen_reader.header.len;
+        let log_len = gen_reader.reader.get_ref().metadata()?.len();
+        let cipher = gen_reader.cipher.as_deref();
+        let hints = match (gen_reader.header.format, from) {
+            (LogFormat::Binary, None) => {
+                hintfile::read(&hint_file(&self.path, gen), gen, log_len, cipher)?
+            }
+            _ => None,
+        };
+        let options = &self.options;
+        let (end, tail) = match gen_reader.header.format {
+            // Nothing appends to legacy logs
+            LogFormat::Json if from.is_some() => return Ok(()),
+            LogFormat::Json => {
+                let (_, tail) = load_json(gen, &mut gen_reader.reader, store, options)?;
+                (log_len, tail)
+            }
+            LogFormat::Binary => match hints {
+                Some(hints) => {
+                    load_hints(gen, hints, store, options);
+                    (log_len, Tail::Clean)
+                }
+                None => {
+                    let from = from.unwrap_or(0).max(header_len);
+                    let (_, tail) = load(gen, &mut gen_reader, from, store, options)?;
+                    (gen_reader.reader.stream_position()?, tail)
+                }
+            },
+        };
+        let end = match tail {
+            Tail::Clean => end,
+            Tail::Torn(offset) if newest => offset,
+            Tail::Torn(offset) => return Err(KvsError::CorruptLog { gen, offset }),
+        };
+        self.loaded.insert(gen, end);
+        Ok(())
+    }
+
+    /// Whether compaction has changed logs already loaded: one of them is
+    /// gone, or a generation has appeared below the newest one loaded.
+    fn compacted(&self, gen_list: &[u64]) -> bool {
+        let newest = self.loaded.keys().next_back();
+        self.loaded.keys().any(|gen| !gen_list.contains(gen))
+            || gen_list
+                .iter()
+                .any(|gen| !self.loaded.contains_key(gen) && Some(gen) < newest)
+    }
+}
 
 impl KvStore {
+    /// Open the store at `path` with the default options.
     #[logfn(Trace)]
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the store at `path` for reading only, with the default options.
+    ///
+    /// Nothing in the directory is created or changed. The store never
+    /// compacts, leaves a partly written record at the end of the newest
+    /// log alone instead of truncating it, and fails every write with
+    /// `ReadOnly`.
+    ///
+    /// Any number of read-only stores can have a directory open alongside
+    /// each other and one store opened for writing; `refresh` picks up what
+    /// that writer has appended since. They share a lock with each other
+    /// while they read the logs, which a writer only takes exclusively while
+    /// it recovers them on open, so they never read logs being recovered but
+    /// don't keep a writer from opening the store either.
+    #[logfn(Trace)]
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_read_only_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Start building a set of options to open a store with.
+    pub fn builder() -> KvStoreOptions {
+        KvStoreOptions::new()
+    }
+
+    /// Sequence number of the most recent write.
+    pub fn last_seq(&self) -> u64 {
+        self.store.read().unwrap().last_seq
+    }
+
+    /// Value `key` had just after the write with sequence number `seq`.
+    ///
+    /// Returns `None` if the key didn't exist then, or if the version it had
+    /// then is no longer retained.
+    #[logfn(Trace)]
+    pub fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let store = self.store.read().unwrap();
+        let version = store
+            .versions(&self.tree, key)
+            .into_iter()
+            .rev()
+            .find(|(location, _)| location.seq <= seq);
+        match version {
+            Some((location, false)) if !location.is_expired(now_millis()) => {
+                Ok(Some(self.reader.read_value(location)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Every retained version of `key`, oldest first, ending with the
+    /// current one.
+    #[logfn(Trace)]
+    pub fn history(&self, key: &[u8]) -> Result<Vec<KeyVersion>> {
+        let store = self.store.read().unwrap();
+        let now = now_millis();
+        store
+            .versions(&self.tree, key)
+            .into_iter()
+            .map(|(location, removed)| {
+                let value = if removed || location.is_expired(now) {
+                    None
+                } else {
+                    Some(self.reader.read_value(location)?)
+                };
+                Ok(KeyVersion {
+                    seq: location.seq,
+                    value,
+                })
+            })
+            .collect::<Result<_>>()
+    }
+
+    /// Hit and miss counts and current size of the value cache, which is
+    /// shared by every clone of the store.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.reader.cache.stats()
+    }
+
+    /// Compact this handle's keyspace now, without waiting for enough of the
+    /// log to go stale.
+    ///
+    /// The live records of the keyspace are copied into a new generation,
+    /// and older generations are deleted once no keyspace refers to them.
+    /// Writes wait until the compaction is done.
+    #[logfn(Trace)]
+    pub fn compact(&self) -> Result<()> {
+        self.writer()?.compact(Some(&self.tree))
+    }
+
+    /// Rewrite every log of the store at `path` that is in an older format
+    /// than the one this version of kvs writes, opening it with `options`.
+    ///
+    /// The whole store is compacted into a new generation, so this takes as
+    /// long as a compaction and needs as much free space as the live data.
+    /// With `dry_run`, the logs that would be rewritten are only reported,
+    /// and nothing in the directory changes.
+    ///
+    /// `options` are recorded as the store's own, so should usually start
+    /// from `KvStoreOptions::recorded`. Fails with `HistoryNotRetained`,
+    /// whether or not it is a dry run, if they would keep fewer versions of
+    /// a key than the options the store was last opened with, rather than
+    /// have the compaction drop them.
+    #[logfn(Trace)]
+    pub fn upgrade(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+        dry_run: bool,
+    ) -> Result<UpgradeReport> {
         let path = path.into();
-        create_dir_all(&path)?;
-        let gen_list = gen_list(&path)?;
-        let mut store: HashMap<String, FileLocation> = HashMap::new();
-        let mut readers: HashMap<u64, BufReader<File>> = HashMap::new();
+        let report = |outdated: Vec<(u64, u32)>| UpgradeReport {
+            from_version: outdated
+                .iter()
+                .map(|(_, version)| *version)
+                .min()
+                .unwrap_or(logformat::FORMAT_VERSION),
+            to_version: logformat::FORMAT_VERSION,
+            outdated,
+            dry_run,
+        };
+        StoreMetadata::check(&path, ENGINE_NAME, logformat::FORMAT_VERSION)?;
+        options.retain_at_least(&KvStoreOptions::recorded(&path)?)?;
+        if dry_run {
+            let _reading = DirLock::reading(&path)?;
+            return Ok(report(outdated_gens(&path)?));
+        }
+
+        let store = KvStore::open_with_options(&path, options.clone())?;
+        let outdated = outdated_gens(&path)?;
+        if !outdated.is_empty() {
+            let mut writer = store.writer()?;
+            writer.compact(None)?;
+            let version = logformat::FORMAT_VERSION;
+            writer
+                .compactor
+                .manifest
+                .lock()
+                .unwrap()
+                .set_format_version(version)?;
+            StoreMetadata::record(&path, ENGINE_NAME, version, options.describe())?;
+        }
+        Ok(report(outdated))
+    }
+
+    /// Load what a writer with the store open elsewhere has written since
+    /// this read-only store was opened or last refreshed.
+    ///
+    /// If the writer has compacted in the meantime, the index is rebuilt
+    /// from the new logs. Until then, reads of values in logs compaction
+    /// has deleted fail with a not found error.
+    ///
+    /// A store opened for writing always sees its own writes, so this does
+    /// nothing for one.
+    #[logfn(Trace)]
+    pub fn refresh(&self) -> Result<()> {
+        let follower = match &self.access {
+            Access::ReadOnly(follower) => follower,
+            Access::ReadWrite { .. } => return Ok(()),
+        };
+        let mut follower = follower.lock().unwrap();
+        let _reading = DirLock::reading(&self.reader.path)?;
+        let gen_list = live_gens(&self.reader.path)?;
+        let saved_seq = saved_last_seq(&self.reader.path)?;
+        if follower.compacted(&gen_list) {
+            debug!("KvStore::refresh, logs were compacted, reloading");
+            let loaded = std::mem::take(&mut follower.loaded);
+            let mut store = Index::default();
+            if let Err(e) = follower.load(&gen_list, &mut store) {
+                follower.loaded = loaded;
+                return Err(e);
+            }
+            store.last_seq = store.last_seq.max(saved_seq);
+            *self.store.write().unwrap() = store;
+        } else {
+            let mut store = self.store.write().unwrap();
+            follower.load(&gen_list, &mut store)?;
+            store.last_seq = store.last_seq.max(saved_seq);
+        }
+
+        if let (Some(first), Some(last)) = (gen_list.first(), gen_list.last()) {
+            self.reader.safe_point.store(*first, Ordering::SeqCst);
+            self.reader.active.store(*last, Ordering::SeqCst);
+        }
+        self.reader.close_stale_readers();
+        self.reader.unmap_stale();
+        Ok(())
+    }
+
+    #[logfn(Trace)]
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        create_dir_all(&*path)?;
+        // Nothing is written, not even the lock, to another engine's store
+        StoreMetadata::check(&path, ENGINE_NAME, logformat::FORMAT_VERSION)?;
+        let lock = DirLock::exclusive(&path)?;
+        let recovering = DirLock::recovering(&path)?;
+        remove_leftovers(&path)?;
+        let gen_list = live_gens(&path)?;
+        let keys = Arc::new(Keyring::new(
+            options.encryption_key.as_ref(),
+            &options.old_encryption_keys,
+        ));
+        let mut store = Index::default();
         let mut compactible: u64 = 0;
+        let mut latest_appendable = true;
+        // Format of the oldest log
+        let mut format_version = logformat::FORMAT_VERSION;
         for gen in &gen_list {
-            let mut reader = get_reader(&path, *gen)?;
-            compactible += load(*gen, &mut reader, &mut store)?;
-            readers.insert(*gen, reader);
+            let newest = Some(gen) == gen_list.last();
+            let mut gen_reader = match GenReader::open(&path, *gen, &keys) {
+                Err(KvsError::InvalidLogHeader) if newest => {
+                    warn!("Log for generation {} has a torn header, truncating", gen);
+                    truncate_log(&path, *gen, 0)?;
+                    latest_appendable = true;
+                    continue;
+                }
+                // Listed, but the store closed before creating it
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                    if !newest {
+                        return Err(KvsError::MissingLog(*gen));
+                    }
+                    latest_appendable = true;
+                    continue;
+                }
+                result => result?,
+            };
+            let log_len = gen_reader.reader.get_ref().metadata()?.len();
+            let cipher = gen_reader.cipher.as_deref();
+            let (gen_compactible, tail) = match gen_reader.header.format {
+                LogFormat::Json => load_json(*gen, &mut gen_reader.reader, &mut store, &options)?,
+                LogFormat::Binary => {
+                    match hintfile::read(&hint_file(&path, *gen), *gen, log_len, cipher)? {
+                        Some(hints) => (load_hints(*gen, hints, &mut store, &options), Tail::Clean),
+                        None => {
+                            let from = gen_reader.header.len;
+                            load(*gen, &mut gen_reader, from, &mut store, &options)?
+                        }
+                    }
+                }
+            };
+            if let Tail::Torn(offset) = tail {
+                if !newest {
+                    return Err(KvsError::CorruptLog { gen: *gen, offset });
+                }
+                warn!(
+                    "Log for generation {} has a torn record at offset {}, truncating",
+                    gen, offset
+                );
+                truncate_log(&path, *gen, offset)?;
+            }
+            compactible += gen_compactible;
+            let header = gen_reader.header;
+            format_version = format_version.min(header.version);
+            // Never append records to a log in an older format, or records
+            // sealed with one key to a log labelled with another
+            latest_appendable = header.format == LogFormat::Binary
+                && (header.len == 0
+                    || (header.version == logformat::FORMAT_VERSION
+                        && header.key_id == keys.current_id()));
+        }
+        if format_version < logformat::FORMAT_VERSION {
+            warn!(
+                "Store at {:?} has logs in format version {}; `kvs-admin upgrade` rewrites them in version {}",
+                path,
+                format_version,
+                logformat::FORMAT_VERSION
+            );
+        }
+        StoreMetadata::record(&path, ENGINE_NAME, format_version, options.describe())?;
+        // Logs the manifest doesn't list that are older than every live
+        // generation were dropped by a compaction that couldn't delete them.
+        // Others are left alone, and new generations are numbered past them
+        let mut newest_unlisted = 0;
+        for gen in scan_gens(&path)?
+            .into_iter()
+            .filter(|gen| !gen_list.contains(gen))
+        {
+            if gen_list.first().is_some_and(|oldest| gen < *oldest) {
+                warn!("Removing log for generation {}, which was compacted", gen);
+                remove_file(log_file(&path, gen))?;
+                let hint_path = hint_file(&path, gen);
+                if hint_path.exists() {
+                    remove_file(hint_path)?;
+                }
+                continue;
+            }
+            warn!(
+                "Ignoring log for generation {}, which isn't in the manifest",
+                gen
+            );
+            newest_unlisted = gen;
+        }
+        let mut latest_gen = *gen_list.last().unwrap_or(&1);
+        if !latest_appendable || newest_unlisted >= latest_gen {
+            latest_gen = latest_gen.max(newest_unlisted) + 1;
+        }
+        // Compaction may have dropped the most recent writes' records, but
+        // their sequence numbers must never be handed out again
+        store.last_seq = store.last_seq.max(saved_last_seq(&path)?);
+        let mut generations: BTreeSet<u64> = gen_list.iter().copied().collect();
+        generations.insert(latest_gen);
+        let manifest = Manifest::new(&path, format_version, generations, store.last_seq, &options);
+        manifest.save()?;
+        let writer = new_log_writer(&path, latest_gen, keys.current_id())?;
+        // Recovery is done, so read-only stores can read the logs again
+        drop(recovering);
+        let mut total = 0;
+        for gen in &gen_list {
+            total += log_file(&path, *gen)
+                .metadata()
+                .map_or(0, |meta| meta.len());
         }
-        let latest_gen = *gen_list.last().unwrap_or(&(1 as u64));
-        let writer = KvWriter::new(open_log_file(&path, latest_gen, false)?)?;
-        if latest_gen == 1 && readers.is_empty() {
-            readers.insert(latest_gen, get_reader(&path, latest_gen)?);
+        if !gen_list.contains(&latest_gen) {
+            total += writer.offset;
         }
         debug!(
-            "KvStore::open, gen = {}, compactible = {}, path = {:?}",
-            latest_gen, compactible, path
+            "KvStore::open, gen = {}, compactible = {}, total = {}, path = {:?}",
+            latest_gen, compactible, total, path
         );
-        Ok(KvStore {
-            store,
+
+        let syncer = Arc::new(Syncer {
+            durability: options.durability,
+            file: Mutex::new(writer.writer.get_ref().try_clone()?),
+            group: GroupCommit::default(),
+        });
+        if let Durability::Periodic(interval) = options.durability {
+            Syncer::sync_periodically(Arc::downgrade(&syncer), interval);
+        }
+
+        let store = Arc::new(RwLock::new(store));
+        let pins = Arc::new(Mutex::new(Pins::default()));
+        let reader = KvReader {
+            path: path.clone(),
+            keys: keys.clone(),
+            safe_point: Arc::new(AtomicU64::new(*gen_list.first().unwrap_or(&1))),
+            active: Arc::new(AtomicU64::new(latest_gen)),
+            maps: Arc::new(RwLock::new(HashMap::new())),
+            cache: Arc::new(ValueCache::new(options.cache_size)),
+            readers: RefCell::new(HashMap::new()),
+        };
+        let writer = KvStoreWriter {
+            path: path.clone(),
+            store: store.clone(),
+            keys,
             writer,
-            readers,
+            options: options.clone(),
             gen: latest_gen,
             compactible,
+            total,
+            compactor: Compactor {
+                path,
+                options,
+                store: store.clone(),
+                reader: reader.clone(),
+                pins: pins.clone(),
+                manifest: Arc::new(Mutex::new(manifest)),
+            },
+            compaction: None,
+            syncer: syncer.clone(),
+            _lock: lock,
+        };
+
+        Ok(KvStore {
+            tree: String::new(),
+            store,
+            reader,
+            pins,
+            access: Access::ReadWrite {
+                writer: Arc::new(Mutex::new(writer)),
+                syncer,
+            },
+        })
+    }
+
+    /// Open the store at `path` for reading only, as `open_read_only` does.
+    /// Of `options`, only the retention policy, the cache size and the
+    /// encryption keys matter.
+    #[logfn(Trace)]
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        StoreMetadata::check(&path, ENGINE_NAME, logformat::FORMAT_VERSION)?;
+        let reading = DirLock::reading(&path)?;
+        let gen_list = live_gens(&path)?;
+        let keys = Arc::new(Keyring::new(
+            options.encryption_key.as_ref(),
+            &options.old_encryption_keys,
+        ));
+        let mut follower = Follower {
+            path: path.clone(),
+            keys: keys.clone(),
+            options: options.clone(),
+            loaded: BTreeMap::new(),
+        };
+        let mut store = Index::default();
+        follower.load(&gen_list, &mut store)?;
+        store.last_seq = store.last_seq.max(saved_last_seq(&path)?);
+        drop(reading);
+        debug!(
+            "KvStore::open_read_only, gens = {:?}, path = {:?}",
+            gen_list, path
+        );
+
+        let reader = KvReader {
             path,
+            keys,
+            safe_point: Arc::new(AtomicU64::new(*gen_list.first().unwrap_or(&1))),
+            // The newest generation may still be growing
+            active: Arc::new(AtomicU64::new(*gen_list.last().unwrap_or(&1))),
+            maps: Arc::new(RwLock::new(HashMap::new())),
+            cache: Arc::new(ValueCache::new(options.cache_size)),
+            readers: RefCell::new(HashMap::new()),
+        };
+        Ok(KvStore {
+            tree: String::new(),
+            store: Arc::new(RwLock::new(store)),
+            reader,
+            pins: Arc::new(Mutex::new(Pins::default())),
+            access: Access::ReadOnly(Arc::new(Mutex::new(follower))),
         })
     }
 
+    /// The writer, unless the store is read-only.
+    fn writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match &self.access {
+            Access::ReadWrite { writer, .. } => Ok(writer.lock().unwrap()),
+            Access::ReadOnly(_) => Err(KvsError::ReadOnly),
+        }
+    }
+
+    /// Wait for the write just made to be synced, if the store syncs before
+    /// acknowledging writes and hasn't already.
+    ///
+    /// Called once the writer is released, so that other writers can append
+    /// while this one waits, and share its sync.
+    fn commit(&self) -> Result<()> {
+        match &self.access {
+            Access::ReadWrite { syncer, .. } if syncer.durability == Durability::GroupCommit => {
+                syncer.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// Append `record` to the active log, returning the generation and
+    /// offset it was written at, and the frame written.
+    fn append(&mut self, record: &Record) -> Result<(u64, u64, Vec<u8>)> {
+        let gen = self.gen;
+        let offset = self.writer.offset;
+        let frame = record.encode(self.keys.current(), gen, offset);
+        self.writer.write_all(&frame)?;
+        self.writer.flush()?;
+        if self.options.durability == Durability::EveryWrite {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        self.syncer.group.wrote();
+        self.total += self.writer.offset - offset;
+
+        if let Some(max_file_size) = self.options.max_file_size {
+            if self.writer.offset >= max_file_size {
+                self.gen += 1;
+                debug!("Active log is full, moving on to gen {}", self.gen);
+                self.switch_log()?;
+            }
+        }
+        Ok((gen, offset, frame))
+    }
+
+    /// Move writes on to the log for `self.gen`.
+    ///
+    /// Unless durability is off, the log being left is synced first, as the
+    /// syncer only ever syncs the active log.
+    fn switch_log(&mut self) -> Result<()> {
+        if self.options.durability != Durability::None {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        // Listed first, so nothing is ever written to an unlisted log
+        let gen = self.gen;
+        self.compactor.manifest.lock().unwrap().update(|gens| {
+            gens.insert(gen);
+        })?;
+        self.writer = new_log_writer(&self.path, self.gen, self.keys.current_id())?;
+        *self.syncer.file.lock().unwrap() = self.writer.writer.get_ref().try_clone()?;
+        self.compactor
+            .reader
+            .active
+            .store(self.gen, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn write(&mut self, mut record: Record) -> Result<()> {
+        record.compress(self.options.compression, self.options.compression_min_size);
+        let now = now_millis();
+        record.stamp(self.store.read().unwrap().last_seq, now);
+        let (gen, offset, frame) = self.append(&record)?;
+        let mut store = self.store.write().unwrap();
+        for location in store.superseded(&record) {
+            self.compactor
+                .reader
+                .cache
+                .remove(location.gen, location.offset);
+        }
+        self.compactible += store.apply(record, gen, offset, &frame, now, &self.options);
+        drop(store);
+        self.maybe_compact()
+    }
+
+    fn set(
+        &mut self,
+        tree: &str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.write(Record::Set {
+            tree: tree.to_owned(),
+            key,
+            value,
+            compressed: false,
+            expires_at,
+            seq: 0,
+            timestamp: 0,
+        })
+    }
+
+    fn remove(&mut self, tree: &str, key: &[u8]) -> Result<()> {
+        match self.store.read().unwrap().get(tree, key) {
+            Some(location) if !location.is_expired(now_millis()) => {}
+            _ => return Err(KvsError::KeyNotFound),
+        }
+        self.write(Record::Remove {
+            tree: tree.to_owned(),
+            key: key.to_vec(),
+            seq: 0,
+            timestamp: 0,
+        })
+    }
+
+    /// Write `records` as a single batch record, so that a torn write loses
+    /// all of them.
+    fn write_batch(&mut self, records: Vec<Record>) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.write(Record::Batch(records))
+    }
+
+    /// Drop keyspace `tree`, returning whether there was anything in it.
+    fn drop_tree(&mut self, tree: &str) -> Result<bool> {
+        if !self.store.read().unwrap().trees.contains_key(tree) {
+            return Ok(false);
+        }
+        self.write(Record::DropTree {
+            tree: tree.to_owned(),
+            seq: 0,
+            timestamp: 0,
+        })?;
+        Ok(true)
+    }
+
+    /// Compact keyspace `tree` in the calling thread, once any background
+    /// compaction has finished.
+    fn compact(&mut self, only: Option<&str>) -> Result<()> {
+        if let Some(handle) = self.compaction.take() {
+            let _ = handle.join();
+        }
+        let compaction_gen = self.next_compaction_gen()?;
+        debug!(
+            "Compacting, keyspace = {:?}, compaction gen = {}, new gen = {}",
+            only, compaction_gen, self.gen
+        );
+        self.compactor.compact(compaction_gen, only)
+    }
+
+    /// Move writes on to a new log, leaving the generation in between for
+    /// compacted output, and return that generation.
+    fn next_compaction_gen(&mut self) -> Result<u64> {
+        let compaction_gen = self.gen + 1;
+        self.gen += 2;
+        self.switch_log()?;
+        Ok(compaction_gen)
+    }
+
+    /// Start a background compaction if enough of the log is stale.
+    ///
+    /// The active log is retired and writes move on to a new one, leaving
+    /// the generation in between for the compacted output. Only one
+    /// compaction runs at a time.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.options.should_compact(self.compactible, self.total) {
+            return Ok(());
+        }
+        if let Some(handle) = &self.compaction {
+            if !handle.is_finished() {
+                return Ok(());
+            }
+        }
+        if let Some(handle) = self.compaction.take() {
+            let _ = handle.join();
+        }
+
+        let compaction_gen = self.next_compaction_gen()?;
+        debug!(
+            "Compacting, compactible = {}, compaction gen = {}, new gen = {}",
+            self.compactible, compaction_gen, self.gen
+        );
+        self.total = self.total.saturating_sub(self.compactible);
+        self.compactible = 0;
+
+        let compactor = self.compactor.clone();
+        self.compaction = Some(thread::spawn(move || {
+            if let Err(e) = compactor.compact(compaction_gen, None) {
+                error!(
+                    "Compaction into generation {} failed: {}",
+                    compaction_gen, e
+                );
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        // Don't leave a compaction deleting files behind a later `open`
+        if let Some(handle) = self.compaction.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Copies live records out of the retired generations.
+#[derive(Clone, Debug)]
+struct Compactor {
+    path: Arc<PathBuf>,
+    options: KvStoreOptions,
+    store: Arc<RwLock<Index>>,
+    reader: KvReader,
+    pins: Arc<Mutex<Pins>>,
+    manifest: Arc<Mutex<Manifest>>,
+}
+
+impl Compactor {
+    /// Rewrite every live record older than `compaction_gen` into
+    /// `compaction_gen`, along with the older versions the retention policy
+    /// still keeps, then delete the older generations.
+    ///
+    /// If `only` names a keyspace, just its records are rewritten, and only
+    /// the generations older than anything the index still refers to are
+    /// deleted. Deleting no more than such a prefix keeps a removal from
+    /// going while an older value it hides stays on disk.
+    ///
+    /// Generations that a snapshot still reads from are renamed out of the
+    /// way instead, and deleted once the last such snapshot is dropped.
+    ///
+    /// Records are copied without holding the index lock. The output goes to
+    /// a temporary file that is only renamed into place once it is complete,
+    /// and the index is then pointed at the copies in one step, skipping keys
+    /// that were overwritten or removed in the meantime. The manifest then
+    /// swaps the old generations for the new one, and only the generations
+    /// it dropped are deleted.
     #[logfn(Trace)]
-    fn compact(&mut self) -> Result<()> {
-        debug!("Compacting, compactible = {}", self.compactible);
-        // Get list of files we need to delete
-        let list = gen_list(&self.path)?;
-
-        // Increment the generation counter
-        self.gen += 1;
-        debug!("Compacting, new gen = {}", self.gen);
-
-        // New writer, and clear out the store
-        self.writer = KvWriter::new(open_log_file(&self.path, self.gen, false)?)?;
-        let store = self.store.clone();
-        self.store.clear();
-
-        // Read from the old files and write the new
-        for (key, location) in store.iter() {
-            let reader = self
-                .readers
-                .get_mut(&location.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(location.offset))?;
-            let command = serde_json::from_reader(reader.take(location.length as u64))?;
-            if let KvsCommands::Set { value, .. } = command {
-                self.set(key.to_string(), value)?;
-            } else {
-                return Err(KvsError::UnexpectedCommandType);
+    fn compact(&self, compaction_gen: u64, only: Option<&str>) -> Result<()> {
+        let now = now_millis();
+        let compacted = |name: &str| only.is_none_or(|only| only == name);
+        // Each key's history comes before its current value, so the copies
+        // are replayed in order
+        let mut live = Vec::new();
+        {
+            let store = self.store.read().unwrap();
+            for (name, tree) in store.trees.iter().filter(|(name, _)| compacted(name)) {
+                for (key, versions) in &tree.history {
+                    let current = tree.keys.get(key);
+                    let retained = retained(versions, current, now, &self.options);
+                    for (version, retained) in versions.iter().zip(retained) {
+                        if retained && version.location.gen < compaction_gen {
+                            live.push((name.clone(), key.clone(), version.clone()));
+                        }
+                    }
+                }
+                for (key, location) in &tree.keys {
+                    if location.gen < compaction_gen && !location.is_expired(now) {
+                        let version = Version {
+                            location: location.clone(),
+                            removed: false,
+                        };
+                        live.push((name.clone(), key.clone(), version));
+                    }
+                }
             }
         }
 
-        // Delete all the old files
-        for gen in list {
-            let path = log_file(&self.path, gen)?;
-            debug!("Compacting, deleting gen file {:?}", path);
-            remove_file(path)?;
+        let log_path = log_file(&self.path, compaction_gen);
+        let temp_path = compaction_file(&log_path);
+        let mut writer = KvWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&temp_path)?,
+        )?;
+        let keys = &self.reader.keys;
+        logformat::write_header(&mut writer, keys.current_id())?;
+        let mut moved = Vec::with_capacity(live.len());
+        let mut hints = Vec::with_capacity(live.len());
+        for (tree, key, version) in live {
+            let location = version.location;
+            let mut record = self.reader.read_record(&location)?;
+            // Records from before sequence numbers were stored get theirs now
+            if let Record::Set { seq, timestamp, .. } | Record::Remove { seq, timestamp, .. } =
+                &mut record
+            {
+                *seq = location.seq;
+                *timestamp = location.timestamp;
+            }
+            record.compress(self.options.compression, self.options.compression_min_size);
+            let offset = writer.offset;
+            writer.write_all(&record.encode(keys.current(), compaction_gen, offset))?;
+            let new_location = FileLocation {
+                gen: compaction_gen,
+                offset,
+                length: writer.offset - offset,
+                ..location.clone()
+            };
+            hints.push(HintEntry {
+                tree: tree.clone(),
+                key: key.clone(),
+                offset,
+                length: new_location.length,
+                seq: location.seq,
+                timestamp: location.timestamp,
+                expires_at: location.expires_at,
+                tombstone: version.removed,
+            });
+            moved.push((tree, key, location, new_location));
         }
+        writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
+        let log_len = writer.offset;
+        drop(writer);
+
+        // The hint goes into place first; a hint without its log is ignored
+        let hint_path = hint_file(&self.path, compaction_gen);
+        let temp_hint_path = compaction_file(&hint_path);
+        hintfile::write(
+            &temp_hint_path,
+            compaction_gen,
+            log_len,
+            &hints,
+            keys.current(),
+        )?;
+        rename(&temp_hint_path, &hint_path)?;
+        rename(&temp_path, &log_path)?;
+
+        let safe_point =
+            {
+                let mut store = self.store.write().unwrap();
+                for (tree, key, old_location, new_location) in moved {
+                    // The keyspace may have been dropped in the meantime
+                    let tree = match store.trees.get_mut(&tree) {
+                        Some(tree) => tree,
+                        None => continue,
+                    };
+                    let cache = &self.reader.cache;
+                    if tree.keys.get(&key) == Some(&old_location) {
+                        cache.remap(
+                            (old_location.gen, old_location.offset),
+                            (new_location.gen, new_location.offset),
+                        );
+                        tree.keys.insert(key, new_location);
+                    } else if let Some(version) = tree.history.get_mut(&key).and_then(|versions| {
+                        versions.iter_mut().find(|v| v.location == old_location)
+                    }) {
+                        cache.remap(
+                            (old_location.gen, old_location.offset),
+                            (new_location.gen, new_location.offset),
+                        );
+                        version.location = new_location;
+                    }
+                }
+                // Expired values and versions no longer retained weren't copied,
+                // and go along with the old generations
+                for (_, tree) in store.trees.iter_mut().filter(|(name, _)| compacted(name)) {
+                    tree.keys
+                        .retain(|_, location| location.gen >= compaction_gen);
+                    tree.history.retain(|_, versions| {
+                        versions.retain(|version| version.location.gen >= compaction_gen);
+                        !versions.is_empty()
+                    });
+                }
+                store.trees.retain(|_, tree| !tree.is_empty());
+                let safe_point = store
+                    .oldest_gen()
+                    .map_or(compaction_gen, |oldest| oldest.min(compaction_gen));
+                self.reader.safe_point.store(safe_point, Ordering::SeqCst);
+                safe_point
+            };
+        self.reader.close_stale_readers();
+        self.reader.unmap_stale();
 
-        // Generate new readers
-        self.readers.clear();
-        for gen in gen_list(&self.path)? {
-            let reader = get_reader(&self.path, gen)?;
-            self.readers.insert(gen, reader);
+        // Saved, as the dropped generations may hold the most recent writes
+        let last_seq = self.store.read().unwrap().last_seq;
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.saw_seq(last_seq);
+        let mut dropped = Vec::new();
+        manifest.update(|gens| {
+            gens.insert(compaction_gen);
+            dropped = gens
+                .iter()
+                .copied()
+                .filter(|gen| *gen < safe_point)
+                .collect();
+            gens.retain(|gen| *gen >= safe_point);
+        })?;
+        drop(manifest);
+
+        let mut pins = self.pins.lock().unwrap();
+        for gen in dropped {
+            let path = log_file(&self.path, gen);
+            if pins.counts.contains_key(&gen) {
+                debug!("Compacting, retiring pinned gen file {:?}", path);
+                rename(&path, retired_file(&path))?;
+                pins.retired.insert(gen);
+            } else {
+                debug!("Compacting, deleting gen file {:?}", path);
+                remove_file(path)?;
+            }
+            let hint_path = hint_file(&self.path, gen);
+            if hint_path.exists() {
+                remove_file(hint_path)?;
+            }
         }
 
         Ok(())
@@ -139,100 +1609,454 @@ impl KvStore {
 }
 
 impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
     #[logfn(Trace)]
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        debug!("KvStore::get({})", key);
-        match self.store.get(&key) {
-            None => Ok(None),
-            Some(location) => {
-                let reader = self
-                    .readers
-                    .get_mut(&location.gen)
-                    .expect("Cannot find log reader");
-                reader.seek(SeekFrom::Start(location.offset))?;
-                let command = serde_json::from_reader(reader.take(location.length as u64))?;
-                if let KvsCommands::Set { value, .. } = command {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
-                }
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        debug!("KvStore::get({})", String::from_utf8_lossy(key));
+        let store = self.store.read().unwrap();
+        match store.get(&self.tree, key) {
+            Some(location) if !location.is_expired(now_millis()) => {
+                Ok(Some(self.reader.read_value(location)?))
             }
+            _ => Ok(None),
         }
     }
 
     #[logfn(Trace)]
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        debug!("KvStore::set({}, {})", key, value);
-        let original_offset = self.writer.offset;
-        serde_json::to_writer(
-            &mut self.writer,
-            &KvsCommands::Set {
-                key: key.clone(),
-                value: value.clone(),
-            },
-        )?;
-        self.writer.flush()?;
-        let old_location = self.store.insert(
-            key,
-            FileLocation::new(
-                self.gen,
-                original_offset,
-                self.writer.offset - original_offset,
-            ),
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        debug!(
+            "KvStore::set({}, {})",
+            String::from_utf8_lossy(&key),
+            String::from_utf8_lossy(&value)
         );
-        if let Some(location) = old_location {
-            self.compactible += location.length;
-            if self.compactible >= COMPACTION_THRESHOLD {
-                self.compact()?;
+        self.writer()?.set(&self.tree, key, value, None)?;
+        self.commit()
+    }
+
+    #[logfn(Trace)]
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        debug!("KvStore::remove({})", String::from_utf8_lossy(key));
+        self.writer()?.remove(&self.tree, key)?;
+        self.commit()
+    }
+
+    #[logfn(Trace)]
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        debug!(
+            "KvStore::set_with_ttl({}, {}, {:?})",
+            String::from_utf8_lossy(&key),
+            String::from_utf8_lossy(&value),
+            ttl
+        );
+        self.writer()?
+            .set(&self.tree, key, value, Some(expiry_time(ttl)))?;
+        self.commit()
+    }
+
+    #[logfn(Trace)]
+    fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        debug!(
+            "KvStore::expire({}, {:?})",
+            String::from_utf8_lossy(key),
+            ttl
+        );
+        // Hold the writer so the value can't change before it's rewritten
+        let mut writer = self.writer()?;
+        let value = match self.store.read().unwrap().get(&self.tree, key) {
+            Some(location) if !location.is_expired(now_millis()) => {
+                self.reader.read_value(location)?
             }
+            _ => return Err(KvsError::KeyNotFound),
+        };
+        writer.set(&self.tree, key.to_vec(), value, Some(expiry_time(ttl)))?;
+        drop(writer);
+        self.commit()
+    }
+
+    #[logfn(Trace)]
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        match self.store.read().unwrap().get(&self.tree, key) {
+            Some(location) if !location.is_expired(now_millis()) => {
+                Ok(location.expires_at.map(time_left))
+            }
+            _ => Err(KvsError::KeyNotFound),
         }
-        Ok(())
     }
 
     #[logfn(Trace)]
-    fn remove(&mut self, key: String) -> Result<()> {
-        debug!("KvStore::remove({})", key);
-        match self.store.remove(&key) {
-            Some(location) => {
-                let orig_offset = self.writer.offset;
-                serde_json::to_writer(&mut self.writer, &KvsCommands::Remove { key })?;
-                self.writer.flush()?;
-                let command_size = self.writer.offset - orig_offset;
-                self.compactible += location.length + command_size;
-                if self.compactible >= COMPACTION_THRESHOLD {
-                    self.compact()?;
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        debug!(
+            "KvStore::compare_and_swap({})",
+            String::from_utf8_lossy(&key)
+        );
+        // Holding the writer keeps the value from changing under the compare
+        let mut writer = self.writer()?;
+        let current = match self.store.read().unwrap().get(&self.tree, &key) {
+            Some(location) if !location.is_expired(now_millis()) => {
+                Some(self.reader.read_value(location)?)
+            }
+            _ => None,
+        };
+        if current != expected {
+            return Ok(false);
+        }
+        match (current, new) {
+            (_, Some(new)) => writer.set(&self.tree, key, new, None)?,
+            (Some(_), None) => writer.remove(&self.tree, &key)?,
+            (None, None) => return Ok(true),
+        }
+        drop(writer);
+        self.commit()?;
+        Ok(true)
+    }
+
+    #[logfn(Trace)]
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        debug!("KvStore::write_batch({} operations)", batch.len());
+        self.writer()?.write_batch(batch.into_records(&self.tree))?;
+        self.commit()
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        let (next, end) = owned_bounds(&range);
+        Ok(Box::new(KvStoreIter {
+            store: self.clone(),
+            next,
+            end,
+        }))
+    }
+
+    #[logfn(Trace)]
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        // Pin under the index lock, so a compaction can't swap locations
+        // between the copy and the pins
+        let store = self.store.read().unwrap();
+        let keydir = store
+            .trees
+            .get(&self.tree)
+            .map_or_else(KeyDir::new, |tree| tree.keys.clone());
+        let gens: BTreeSet<u64> = keydir.values().map(|location| location.gen).collect();
+        let pin = SnapshotPin::new(self.reader.path.clone(), self.pins.clone(), gens);
+        drop(store);
+        Ok(KvStoreSnapshot {
+            keydir: Arc::new(keydir),
+            reader: KvReader {
+                path: self.reader.path.clone(),
+                keys: self.reader.keys.clone(),
+                // Nothing the snapshot reads goes away while it's alive
+                safe_point: Arc::new(AtomicU64::new(0)),
+                active: self.reader.active.clone(),
+                maps: Arc::new(RwLock::new(HashMap::new())),
+                cache: self.reader.cache.clone(),
+                readers: RefCell::new(HashMap::new()),
+            },
+            now: now_millis(),
+            _pin: Arc::new(pin),
+        })
+    }
+
+    fn open_tree(&self, name: &str) -> Result<KvStore> {
+        check_tree_name(name)?;
+        Ok(KvStore {
+            tree: name.to_owned(),
+            ..self.clone()
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let store = self.store.read().unwrap();
+        let now = now_millis();
+        Ok(store
+            .trees
+            .iter()
+            .filter(|(name, tree)| {
+                !name.is_empty() && tree.keys.values().any(|location| !location.is_expired(now))
+            })
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    #[logfn(Trace)]
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        debug!("KvStore::drop_tree({})", name);
+        check_tree_name(name)?;
+        let dropped = self.writer()?.drop_tree(name)?;
+        self.commit()?;
+        Ok(dropped)
+    }
+}
+
+/// Generations that open snapshots still read from.
+#[derive(Debug, Default)]
+struct Pins {
+    /// Number of snapshots reading from each generation.
+    counts: HashMap<u64, usize>,
+    /// Pinned generations that have been compacted away.
+    retired: HashSet<u64>,
+}
+
+/// A snapshot's hold on the generations it reads from.
+#[derive(Debug)]
+struct SnapshotPin {
+    path: Arc<PathBuf>,
+    pins: Arc<Mutex<Pins>>,
+    gens: BTreeSet<u64>,
+}
+
+impl SnapshotPin {
+    fn new(path: Arc<PathBuf>, pins: Arc<Mutex<Pins>>, gens: BTreeSet<u64>) -> SnapshotPin {
+        {
+            let mut pins = pins.lock().unwrap();
+            for gen in &gens {
+                *pins.counts.entry(*gen).or_insert(0) += 1;
+            }
+        }
+        SnapshotPin { path, pins, gens }
+    }
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        for gen in &self.gens {
+            let count = pins.counts.get_mut(gen).unwrap();
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            pins.counts.remove(gen);
+            if pins.retired.remove(gen) {
+                let path = retired_file(&log_file(&self.path, *gen));
+                debug!("Deleting unpinned gen file {:?}", path);
+                if let Err(e) = remove_file(&path) {
+                    error!("Failed to delete {:?}: {}", path, e);
                 }
-                Ok(())
             }
-            None => Err(KvsError::KeyNotFound),
         }
     }
 }
 
+/// A `KvStore` as it was when `snapshot` was called.
+///
+/// Holds a copy of the index, and keeps the log files it refers to on disk
+/// until the snapshot and all its clones are dropped.
+#[derive(Clone, Debug)]
+pub struct KvStoreSnapshot {
+    keydir: Arc<KeyDir>,
+    reader: KvReader,
+    /// When the snapshot was taken, for deciding what has expired.
+    now: u64,
+    _pin: Arc<SnapshotPin>,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    #[logfn(Trace)]
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.keydir.get(key) {
+            Some(location) if !location.is_expired(self.now) => {
+                Ok(Some(self.reader.read_value(location)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        let (next, end) = owned_bounds(&range);
+        Ok(Box::new(SnapshotIter {
+            snapshot: self.clone(),
+            next,
+            end,
+        }))
+    }
+}
+
+/// Walks a `KvStore` in key order, looking up one key per step.
+struct KvStoreIter {
+    store: KvStore,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for KvStoreIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if is_empty_range(&self.next, &self.end) {
+            return None;
+        }
+        let store = self.store.store.read().unwrap();
+        let tree = store.trees.get(&self.store.tree)?;
+        let (key, location) = next_live(&tree.keys, &self.next, &self.end, now_millis())?;
+        self.next = Bound::Excluded(key.clone());
+        Some(
+            self.store
+                .reader
+                .read_value(location)
+                .map(|value| (key.clone(), value)),
+        )
+    }
+}
+
+/// Walks a `KvStoreSnapshot` in key order.
+struct SnapshotIter {
+    snapshot: KvStoreSnapshot,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if is_empty_range(&self.next, &self.end) {
+            return None;
+        }
+        let snapshot = &self.snapshot;
+        let (key, location) = next_live(&snapshot.keydir, &self.next, &self.end, snapshot.now)?;
+        self.next = Bound::Excluded(key.clone());
+        Some(
+            snapshot
+                .reader
+                .read_value(location)
+                .map(|value| (key.clone(), value)),
+        )
+    }
+}
+
+/// First key from `next` up to `end` whose value hadn't expired at `now`.
+fn next_live<'a>(
+    keydir: &'a KeyDir,
+    next: &Bound<Vec<u8>>,
+    end: &Bound<Vec<u8>>,
+    now: u64,
+) -> Option<(&'a Vec<u8>, &'a FileLocation)> {
+    keydir
+        .range((next.clone(), end.clone()))
+        .find(|(_, location)| !location.is_expired(now))
+}
+
+/// Every live generation of the store in `path`, oldest first: those its
+/// manifest lists, or every log in the directory if it predates manifests.
+#[logfn(Trace)]
+fn live_gens(path: &Path) -> Result<Vec<u64>> {
+    match Manifest::read(path)? {
+        Some(manifest) if manifest.format_version() > logformat::FORMAT_VERSION => Err(
+            KvsError::UnsupportedFormatVersion(manifest.format_version()),
+        ),
+        Some(manifest) => Ok(manifest.generations()),
+        None => scan_gens(path),
+    }
+}
+
+/// Sequence number the manifest of the store in `path` says writes carry on
+/// from, or 0 if it doesn't have one.
+fn saved_last_seq(path: &Path) -> Result<u64> {
+    Ok(Manifest::read(path)?.map_or(0, |manifest| manifest.last_seq()))
+}
+
+/// The live generations of the store in `path` whose logs are in an older
+/// format than the current one, along with the format of each.
+fn outdated_gens(path: &Path) -> Result<Vec<(u64, u32)>> {
+    let mut outdated = Vec::new();
+    for gen in live_gens(path)? {
+        let mut reader = match open_gen_file(path, gen) {
+            // The newest generation may not have been created yet
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => BufReader::new(result?),
+        };
+        match logformat::read_header(&mut reader) {
+            Ok(header) if header.version < logformat::FORMAT_VERSION => {
+                outdated.push((gen, header.version))
+            }
+            // A torn header is the newest log's, which open truncates
+            Ok(_) | Err(KvsError::InvalidLogHeader) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(outdated)
+}
+
+/// Every generation with a log in `path`, oldest first.
 #[logfn(Trace)]
-fn gen_list(path: &PathBuf) -> Result<Vec<u64>> {
+fn scan_gens(path: &Path) -> Result<Vec<u64>> {
     let pathbufs: Vec<PathBuf> = read_dir(path)?.flatten().map(|d| d.path()).collect();
     let mut numbers: Vec<u64> = pathbufs
         .iter()
-        .map(|p| p.file_stem())
-        .flatten()
-        .map(|s| s.to_str())
-        .flatten()
-        .map(|s| s.parse::<u64>())
-        .flatten()
+        .filter(|p| p.extension() == Some(OsStr::new("log")))
+        .filter_map(|p| p.file_stem())
+        .filter_map(|s| s.to_str())
+        .flat_map(|s| s.parse::<u64>())
         .collect::<Vec<u64>>();
-    numbers.sort();
+    numbers.sort_unstable();
     Ok(numbers)
 }
 
 #[logfn(Trace)]
-fn log_file(path: &PathBuf, gen: u64) -> Result<PathBuf> {
-    Ok(path.join(format!("{}.log", gen.to_string())))
+fn log_file(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.log", gen))
+}
+
+fn hint_file(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.hint", gen))
+}
+
+/// Where a compaction writes `file` until it is complete.
+fn compaction_file(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".compact");
+    PathBuf::from(name)
+}
+
+/// Where a compacted log that a snapshot still needs is kept.
+fn retired_file(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".retired");
+    PathBuf::from(name)
+}
+
+/// Remove output left behind by a compaction or manifest or metadata update
+/// that was interrupted, and logs that were only kept for snapshots.
+///
+/// Only files named exactly as the store names them are removed, so that
+/// anything else someone keeps in the directory is left alone.
+#[logfn(Trace)]
+fn remove_leftovers(path: &Path) -> Result<()> {
+    for entry in read_dir(path)?.flatten() {
+        let file_name = entry.file_name();
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let is_gen_file = |suffix: &str| {
+            name.strip_suffix(suffix)
+                .is_some_and(|gen| gen.parse::<u64>().is_ok())
+        };
+        let entry_path = entry.path();
+        if is_gen_file(".log.compact") || is_gen_file(".hint.compact") {
+            warn!("Removing incomplete compaction output {:?}", entry_path);
+            remove_file(entry_path)?;
+        } else if name == manifest::temp_file_name(MANIFEST_FILE)
+            || name == manifest::temp_file_name(METADATA_FILE)
+        {
+            debug!("Removing incomplete update {:?}", entry_path);
+            remove_file(entry_path)?;
+        } else if is_gen_file(".log.retired") {
+            debug!("Removing retired log {:?}", entry_path);
+            remove_file(entry_path)?;
+        }
+    }
+    Ok(())
 }
 
 #[logfn(Trace)]
-fn open_log_file(path: &PathBuf, gen: u64, readonly: bool) -> Result<File> {
-    let log_file_path = log_file(path, gen)?;
+fn open_log_file(path: &Path, gen: u64, readonly: bool) -> Result<File> {
+    let log_file_path = log_file(path, gen);
 
     if readonly {
         Ok(OpenOptions::new().read(true).open(log_file_path)?)
@@ -245,44 +2069,183 @@ fn open_log_file(path: &PathBuf, gen: u64, readonly: bool) -> Result<File> {
     }
 }
 
+/// Open the log for `gen` for appending, writing the file header for key
+/// `key_id` if the file is new.
 #[logfn(Trace)]
-fn get_reader(path: &PathBuf, gen: u64) -> Result<BufReader<File>> {
-    Ok(BufReader::new(open_log_file(path, gen, true)?))
+fn new_log_writer(path: &Path, gen: u64, key_id: u32) -> Result<KvWriter<File>> {
+    let mut writer = KvWriter::new(open_log_file(path, gen, false)?)?;
+    if writer.offset == 0 {
+        logformat::write_header(&mut writer, key_id)?;
+        writer.flush()?;
+    }
+    Ok(writer)
 }
 
+/// Cut a log file short, dropping a partially written record at its end.
+#[logfn(Trace)]
+fn truncate_log(path: &Path, gen: u64, length: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(log_file(path, gen))?;
+    file.set_len(length)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Open the log for `gen` for reading, or its retired copy if compaction
+/// has moved it aside for a snapshot.
+fn open_gen_file(path: &Path, gen: u64) -> Result<File> {
+    match open_log_file(path, gen, true) {
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(File::open(retired_file(&log_file(path, gen)))?)
+        }
+        result => result,
+    }
+}
+
+/// Decode the record held in `frame`, read from `location` in a log with
+/// the given header, which is encrypted with `cipher` if at all.
+fn decode_record(
+    header: &Header,
+    cipher: Option<&Cipher>,
+    frame: &[u8],
+    location: &FileLocation,
+) -> Result<Record> {
+    let (gen, offset) = (location.gen, location.offset);
+    match header.format {
+        LogFormat::Json => Record::from_command(serde_json::from_slice(frame)?),
+        LogFormat::Binary => match Record::decode(frame, cipher, gen, offset) {
+            Ok(record) => Ok(record),
+            Err(DecodeError::Corrupt) => Err(KvsError::ChecksumMismatch { gen, offset }),
+            Err(DecodeError::Tampered) => Err(KvsError::Tampered { gen, offset }),
+        },
+    }
+}
+
+/// How a log file ends.
+#[derive(Debug, PartialEq, Eq)]
+enum Tail {
+    Clean,
+    /// The last record, starting at this offset, was only partly written or
+    /// fails its checksum.
+    Torn(u64),
+}
+
+/// Rebuild the index from a binary log, starting with the record at `from`,
+/// and return the number of stale bytes.
+///
+/// Values that have expired count as stale, and their keys are dropped.
+///
+/// A damaged record that isn't the last one in the file, or whose damaged
+/// length hides intact records after it, can't be the result of an
+/// interrupted append, and is reported as `CorruptLog`. One that is
+/// intact but fails authentication is reported as `Tampered` wherever it is.
 #[logfn(Trace)]
 fn load(
+    gen: u64,
+    gen_reader: &mut GenReader,
+    from: u64,
+    store: &mut Index,
+    options: &KvStoreOptions,
+) -> Result<(u64, Tail)> {
+    let cipher = gen_reader.cipher.as_deref();
+    let reader = &mut gen_reader.reader;
+    let file_len = reader.get_ref().metadata()?.len();
+    let mut offset = reader.seek(SeekFrom::Start(from))?;
+    let mut compactible = 0;
+    let now = now_millis();
+    loop {
+        let frame = match logformat::read_frame(reader) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok((compactible, torn_tail(gen, reader, offset)?))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let length = frame.len() as u64;
+        match Record::decode(&frame, cipher, gen, offset) {
+            Ok(record) => compactible += store.apply(record, gen, offset, &frame, now, options),
+            Err(DecodeError::Corrupt) if offset + length == file_len => {
+                return Ok((compactible, torn_tail(gen, reader, offset)?))
+            }
+            Err(DecodeError::Corrupt) => return Err(KvsError::CorruptLog { gen, offset }),
+            Err(DecodeError::Tampered) => return Err(KvsError::Tampered { gen, offset }),
+        }
+        offset += length;
+    }
+    Ok((compactible, Tail::Clean))
+}
+
+/// How a log whose last record, at `offset`, is incomplete or fails its
+/// checksum ends.
+///
+/// That is only the result of an interrupted append if intact frames don't
+/// follow the record all the way to the end of the log. If they do, the
+/// record's length was damaged, and the rest of the log must not be
+/// mistaken for a torn tail and truncated.
+fn torn_tail(gen: u64, reader: &mut BufReader<File>, offset: u64) -> Result<Tail> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    if logformat::intact_frames_follow(&rest) {
+        return Err(KvsError::CorruptLog { gen, offset });
+    }
+    Ok(Tail::Torn(offset))
+}
+
+/// Rebuild the index from the hint file for a binary log.
+#[logfn(Trace)]
+fn load_hints(gen: u64, hints: Vec<HintEntry>, store: &mut Index, options: &KvStoreOptions) -> u64 {
+    let mut compactible = 0;
+    let now = now_millis();
+    for hint in hints {
+        let location = FileLocation::new(
+            gen,
+            hint.offset,
+            hint.length,
+            hint.expires_at,
+            hint.seq,
+            hint.timestamp,
+        );
+        compactible += if hint.tombstone {
+            store.remove(&hint.tree, hint.key, location, now, options)
+        } else {
+            store.set(hint.tree, hint.key, location, now, options)
+        };
+    }
+    compactible
+}
+
+/// Rebuild the index from a legacy serde_json log.
+#[logfn(Trace)]
+fn load_json(
     gen: u64,
     reader: &mut BufReader<File>,
-    store: &mut HashMap<String, FileLocation>,
-) -> Result<u64> {
+    store: &mut Index,
+    options: &KvStoreOptions,
+) -> Result<(u64, Tail)> {
     let mut offset = reader.seek(SeekFrom::Start(0))?;
     let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<KvsCommands>();
-    let mut compactible = 0 as u64;
+    let mut compactible = 0;
+    let now = now_millis();
     while let Some(command) = stream.next() {
         let new_offset = stream.byte_offset() as u64;
-        match command? {
+        let command = match command {
+            Ok(command) => command,
+            Err(ref e) if e.is_eof() => return Ok((compactible, Tail::Torn(offset))),
+            Err(e) if e.is_io() => return Err(e.into()),
+            Err(_) => return Err(KvsError::CorruptLog { gen, offset }),
+        };
+        let location = FileLocation::new(gen, offset, new_offset - offset, None, 0, 0);
+        compactible += match command {
             KvsCommands::Set { key, .. } => {
-                if let Some(old_location) = store.insert(
-                    key,
-                    FileLocation {
-                        gen,
-                        offset,
-                        length: new_offset - offset,
-                    },
-                ) {
-                    compactible += old_location.length;
-                }
+                store.set(String::new(), key.into_bytes(), location, now, options)
             }
             KvsCommands::Remove { key } => {
-                if let Some(old_location) = store.remove(&key) {
-                    compactible += old_location.length;
-                }
-                compactible += new_offset - offset;
+                store.remove("", key.into_bytes(), location, now, options)
             }
-            KvsCommands::Get { .. } => return Err(KvsError::UnexpectedCommandType),
-        }
+            _ => return Err(KvsError::UnexpectedCommandType),
+        };
         offset = new_offset;
     }
-    Ok(compactible)
+    Ok((compactible, Tail::Clean))
 }
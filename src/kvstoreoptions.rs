@@ -0,0 +1,281 @@
+use crate::manifest::Manifest;
+use crate::{Compression, Durability, EncryptionKey, KvStore, KvsError, Result, StoreMetadata};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_COMPRESSION_MIN_SIZE: usize = 64;
+
+/// Settings used when opening a `KvStore`.
+///
+/// ```no_run
+/// # use kvs::{KvStore, Result};
+/// # fn main() -> Result<()> {
+/// let store = KvStore::builder()
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .compaction_ratio(0.5)
+///     .max_file_size(256 * 1024 * 1024)
+///     .open("/var/lib/kvs")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_ratio: Option<f64>,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) compaction_disabled: bool,
+    pub(crate) retain_versions: Option<usize>,
+    pub(crate) retain_for: Option<Duration>,
+    pub(crate) durability: Durability,
+    pub(crate) cache_size: u64,
+    pub(crate) compression: Compression,
+    pub(crate) compression_min_size: usize,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) old_encryption_keys: Vec<EncryptionKey>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: None,
+            max_file_size: None,
+            compaction_disabled: false,
+            retain_versions: None,
+            retain_for: None,
+            durability: Durability::default(),
+            cache_size: 0,
+            compression: Compression::default(),
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            encryption_key: None,
+            old_encryption_keys: Vec::new(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The options the store at `path` was last opened with, as recorded in
+    /// its metadata, or in its manifest if it predates metadata.
+    ///
+    /// Settings that aren't recorded, such as the cache size, are left at
+    /// their defaults. Encryption keys never are, so have to be added.
+    pub fn recorded(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let recorded = match StoreMetadata::read(path)? {
+            Some(metadata) => metadata.options,
+            None => Manifest::read(path)?
+                .map_or_else(BTreeMap::new, |manifest| manifest.options().clone()),
+        };
+        fn corrupt<E>(_: E) -> KvsError {
+            KvsError::CorruptMetadata
+        }
+        let mut options = KvStoreOptions::default();
+        for (name, value) in &recorded {
+            match name.as_str() {
+                "compression" => {
+                    options.compression = value.parse().map_err(|_| match value.as_str() {
+                        "lz4" => KvsError::CompressionUnsupported,
+                        _ => KvsError::CorruptMetadata,
+                    })?
+                }
+                "compression_min_size" => {
+                    options.compression_min_size = value.parse().map_err(corrupt)?
+                }
+                "durability" => options.durability = value.parse().map_err(corrupt)?,
+                "max_file_size" => options.max_file_size = Some(value.parse().map_err(corrupt)?),
+                "retain_versions" => {
+                    options.retain_versions = Some(value.parse().map_err(corrupt)?)
+                }
+                "retain_for_ms" => {
+                    let millis = value.parse().map_err(corrupt)?;
+                    options.retain_for = Some(Duration::from_millis(millis));
+                }
+                // Such as the id of the encryption key
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+
+    /// Number of bytes of overwritten or removed records that must pile up
+    /// before a compaction starts. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Additionally require stale bytes to be at least `ratio` times the live
+    /// bytes before compacting, so large stores aren't rewritten for a small
+    /// amount of garbage. Off by default.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = Some(ratio);
+        self
+    }
+
+    /// Start a new generation once the active log grows past `bytes`.
+    /// Unlimited by default.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Never compact the log.
+    pub fn disable_compaction(mut self, disabled: bool) -> Self {
+        self.compaction_disabled = disabled;
+        self
+    }
+
+    /// Keep up to `versions` versions of every key, counting the current
+    /// one, for `KvStore::get_at` and `KvStore::history`. Only the current
+    /// version is kept by default.
+    pub fn retain_versions(mut self, versions: usize) -> Self {
+        self.retain_versions = Some(versions);
+        self
+    }
+
+    /// Keep every version of a key that was replaced less than `window` ago.
+    /// Combined with `retain_versions`, a version is kept if either setting
+    /// would keep it.
+    pub fn retain_for(mut self, window: Duration) -> Self {
+        self.retain_for = Some(window);
+        self
+    }
+
+    /// When writes are synced to disk. Defaults to
+    /// `Durability::GroupCommit`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Keep up to `bytes` of recently read values in memory, so reading
+    /// them again doesn't go to disk. Off by default.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// How to compress values as they are written. Compaction compresses
+    /// values written before it was turned on. Off by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Leave values shorter than `bytes` uncompressed, as they rarely get
+    /// much smaller. Defaults to 64 bytes.
+    pub fn compression_min_size(mut self, bytes: usize) -> Self {
+        self.compression_min_size = bytes;
+        self
+    }
+
+    /// Encrypt everything written from now on with `key`. Logs written
+    /// before are left as they are until compaction rewrites them.
+    ///
+    /// To rotate keys, open the store with the new key here and the old one
+    /// passed to `old_encryption_key`. Once compaction has rewritten every
+    /// log written with the old key, it is no longer needed.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Accept `key` for reading logs written before the encryption key was
+    /// changed.
+    pub fn old_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.old_encryption_keys.push(key);
+        self
+    }
+
+    /// These options by name, as recorded in a store's manifest and
+    /// metadata. Keys are left out, bar the id of the one to encrypt with.
+    pub(crate) fn describe(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        let mut record = |name: &str, value: String| options.insert(name.to_owned(), value);
+        record("compression", self.compression.to_string());
+        record(
+            "compression_min_size",
+            self.compression_min_size.to_string(),
+        );
+        record("durability", self.durability.to_string());
+        if let Some(key) = &self.encryption_key {
+            record("encryption_key_id", key.id().to_string());
+        }
+        if let Some(bytes) = self.max_file_size {
+            record("max_file_size", bytes.to_string());
+        }
+        if let Some(versions) = self.retain_versions {
+            record("retain_versions", versions.to_string());
+        }
+        if let Some(window) = self.retain_for {
+            record("retain_for_ms", window.as_millis().to_string());
+        }
+        options
+    }
+
+    /// Check that these options keep at least as much history as `other`.
+    pub(crate) fn retain_at_least(&self, other: &KvStoreOptions) -> Result<()> {
+        let versions = |options: &KvStoreOptions| options.retain_versions.unwrap_or(1);
+        if versions(self) < versions(other) {
+            return Err(KvsError::HistoryNotRetained(format!(
+                "{} versions rather than {}",
+                versions(self),
+                versions(other)
+            )));
+        }
+        let window = |options: &KvStoreOptions| options.retain_for.unwrap_or_default();
+        if window(self) < window(other) {
+            return Err(KvsError::HistoryNotRetained(format!(
+                "versions replaced in the last {:?} rather than {:?}",
+                window(self),
+                window(other)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether any versions besides the current one are ever kept.
+    pub(crate) fn keeps_history(&self) -> bool {
+        self.retain_versions.is_some_and(|versions| versions > 1) || self.retain_for.is_some()
+    }
+
+    /// Whether to keep a version that has `newer` versions after it and was
+    /// replaced at `replaced_at`, in milliseconds since the Unix epoch.
+    pub(crate) fn retains(&self, newer: usize, replaced_at: u64, now: u64) -> bool {
+        let by_count = self
+            .retain_versions
+            .is_some_and(|versions| newer < versions);
+        let by_age = self
+            .retain_for
+            .is_some_and(|window| replaced_at.saturating_add(window.as_millis() as u64) > now);
+        by_count || by_age
+    }
+
+    /// Whether `dead` stale bytes out of `total` warrant a compaction.
+    pub(crate) fn should_compact(&self, dead: u64, total: u64) -> bool {
+        if self.compaction_disabled || dead < self.compaction_threshold {
+            return false;
+        }
+        match self.compaction_ratio {
+            Some(ratio) => dead as f64 >= ratio * total.saturating_sub(dead) as f64,
+            None => true,
+        }
+    }
+
+    /// Open the store at `path` with these options.
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
+
+    /// Open the store at `path` for reading only, with these options.
+    pub fn open_read_only(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_read_only_with_options(path, self)
+    }
+}
@@ -7,15 +7,38 @@ extern crate log_derive;
 extern crate serde;
 extern crate structopt;
 
+pub mod compression;
+mod dirlock;
+pub mod durability;
+pub mod encryption;
+// `failure_derive` expands to impls nested inside an anonymous const
+#[allow(non_local_definitions)]
 pub mod error;
+mod hintfile;
 pub mod kvsengine;
 pub mod kvstore;
+pub mod kvstoreoptions;
+mod logformat;
+mod manifest;
+pub mod metadata;
+pub mod protocol;
 pub mod sledkvsengine;
+pub mod threadpool;
+pub mod valuecache;
+pub mod writebatch;
 
+pub use compression::Compression;
+pub use durability::Durability;
+pub use encryption::EncryptionKey;
 pub use error::{KvsError, Result};
-pub use kvsengine::KvsEngine;
-pub use kvstore::KvStore;
-pub use sledkvsengine::SledKvsEngine;
+pub use kvsengine::{KvsBytesIterator, KvsEngine, KvsIterator, KvsSnapshot};
+pub use kvstore::{KeyVersion, KvStore, KvStoreSnapshot, UpgradeReport};
+pub use kvstoreoptions::KvStoreOptions;
+pub use metadata::StoreMetadata;
+pub use sledkvsengine::{SledKvsEngine, SledSnapshot};
+pub use threadpool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use valuecache::CacheStats;
+pub use writebatch::WriteBatch;
 
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
@@ -29,5 +52,30 @@ pub enum KvsCommands {
     Remove { key: String },
 
     #[structopt(name = "set")]
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        /// Seconds until the key expires
+        #[structopt(long = "ttl")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+
+    /// Replace a value only if it currently matches `--expected`
+    #[structopt(name = "cas")]
+    CompareAndSwap {
+        key: String,
+        /// Current value to match; leave out to require that the key is missing
+        #[structopt(long = "expected")]
+        expected: Option<String>,
+        /// Value to store; leave out to remove the key
+        #[structopt(long = "new")]
+        new: Option<String>,
+    },
+
+    #[structopt(name = "set-if-absent")]
+    SetIfAbsent { key: String, value: String },
+
+    #[structopt(name = "set-if-present")]
+    SetIfPresent { key: String, value: String },
 }
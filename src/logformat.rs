@@ -0,0 +1,702 @@
+//! On-disk encoding of `KvStore` log files.
+//!
+//! A log file starts with an 8 byte header: the magic bytes `KVSL` followed
+//! by the format version as a little-endian `u32`. The header is followed by
+//! back-to-back records, each framed as
+//!
+//! ```text
+//! +-----------+-----------+--------------------+
+//! | crc32 u32 | len u32   | payload (len bytes)|
+//! +-----------+-----------+--------------------+
+//! ```
+//!
+//! where the CRC covers the length and the payload. A payload is a one byte
+//! record kind and a flags byte, then any optional fields the flags call
+//! for, then the key and (for sets) the value, each prefixed by its length
+//! as a `u32`.
+//!
+//! Format versions:
+//!
+//! 1. The original binary format; the flags byte is always zero.
+//! 2. Sets may carry an expiry time (`FLAG_EXPIRES`), stored as a `u64` of
+//!    milliseconds since the Unix epoch.
+//! 3. Batch records, whose payload after the kind and flags is a run of
+//!    complete set and remove frames. The outer checksum covers them all, so
+//!    a batch is replayed entirely or not at all, while each inner frame can
+//!    still be read on its own.
+//! 4. Sets and removes carry a sequence number and the time they were
+//!    written (`FLAG_VERSIONED`), two `u64`s stored before any expiry time.
+//!    Records from older formats decode with both set to zero.
+//! 5. Records can belong to a named keyspace (`FLAG_TREE`), whose name is
+//!    stored just before the key. Records without it are in the default
+//!    keyspace. Drop-tree records remove a keyspace and everything in it.
+//! 6. The value of a set may be compressed (`FLAG_COMPRESSED`), as an LZ4
+//!    block prefixed by its uncompressed length as a `u32`.
+//! 7. The header goes on with the `u32` id of the key the log is encrypted
+//!    with, or zero if it isn't. In an encrypted log every set, remove and
+//!    drop-tree payload is sealed: a `KIND_SEALED` byte, then the payload
+//!    encrypted as described in `encryption`, bound to the generation and
+//!    offset of its frame. Batch payloads are left as they are, but the
+//!    frames inside them are sealed, and end with a sealed `KIND_BATCH_END`
+//!    frame holding the offset of the batch and the number of records in
+//!    it as a `u64` and a `u32`, so a batch can't be cut short unnoticed.
+//!
+//! Files without the magic bytes are logs written before this format
+//! existed, holding back-to-back serde_json `KvsCommands`. They are still
+//! readable, but never appended to.
+
+use crate::compression::{self, Compression};
+use crate::encryption::Cipher;
+use crate::{KvsCommands, KvsError, Result};
+
+use crc32fast::Hasher;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"KVSL";
+pub const FORMAT_VERSION: u32 = 7;
+/// Version given to logs of serde_json `KvsCommands`, which have no header.
+pub const JSON_FORMAT_VERSION: u32 = 0;
+/// Length of the header of a log in the current format.
+pub const HEADER_LEN: u64 = 12;
+/// Length of the header of a log from before format version 7.
+const OLD_HEADER_LEN: u64 = 8;
+pub const FRAME_HEADER_LEN: u64 = 8;
+/// Distance from the start of a batch frame to its first inner frame.
+pub const BATCH_HEADER_LEN: u64 = FRAME_HEADER_LEN + 2;
+
+const KIND_SET: u8 = 1;
+const KIND_REMOVE: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const KIND_DROP_TREE: u8 = 4;
+const KIND_SEALED: u8 = 5;
+const KIND_BATCH_END: u8 = 6;
+
+const FLAG_EXPIRES: u8 = 1;
+const FLAG_VERSIONED: u8 = 2;
+const FLAG_TREE: u8 = 4;
+const FLAG_COMPRESSED: u8 = 8;
+
+/// Encoding used by a single log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// serde_json `KvsCommands`, as written before the binary format.
+    Json,
+    /// Length-prefixed, checksummed binary records.
+    Binary,
+}
+
+/// What the header of a log file says about it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub format: LogFormat,
+    /// Format version the log was written in.
+    pub version: u32,
+    /// Id of the key the log is encrypted with; zero if it isn't.
+    pub key_id: u32,
+    /// Offset of the first record.
+    pub len: u64,
+}
+
+/// Why a frame couldn't be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The frame fails its checksum or doesn't make sense.
+    Corrupt,
+    /// The frame is intact, but fails authentication or isn't encrypted
+    /// when the log it is in is.
+    Tampered,
+}
+
+/// A single entry in a log file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    Set {
+        /// Keyspace the record belongs to; empty for the default one.
+        tree: String,
+        key: Vec<u8>,
+        /// The value as stored, which may be compressed.
+        value: Vec<u8>,
+        compressed: bool,
+        /// Milliseconds since the Unix epoch after which the value is gone.
+        expires_at: Option<u64>,
+        /// Position of the write in the store's history.
+        seq: u64,
+        /// Milliseconds since the Unix epoch when the write was made.
+        timestamp: u64,
+    },
+    Remove {
+        tree: String,
+        key: Vec<u8>,
+        seq: u64,
+        timestamp: u64,
+    },
+    /// Sets and removes that are applied together or not at all.
+    Batch(Vec<Record>),
+    /// Removal of a whole keyspace.
+    DropTree {
+        tree: String,
+        seq: u64,
+        timestamp: u64,
+    },
+}
+
+impl Record {
+    /// Convert a command read from a legacy JSON log.
+    pub fn from_command(command: KvsCommands) -> Result<Record> {
+        match command {
+            KvsCommands::Set { key, value, .. } => Ok(Record::Set {
+                tree: String::new(),
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                compressed: false,
+                expires_at: None,
+                seq: 0,
+                timestamp: 0,
+            }),
+            KvsCommands::Remove { key } => Ok(Record::Remove {
+                tree: String::new(),
+                key: key.into_bytes(),
+                seq: 0,
+                timestamp: 0,
+            }),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Encode the record as a complete frame, ready to be appended to the
+    /// log for `gen` at `offset`, sealing it with `cipher` if the log is
+    /// encrypted.
+    ///
+    /// A sealed frame can only be opened at the position it was encoded for.
+    pub fn encode(&self, cipher: Option<&Cipher>, gen: u64, offset: u64) -> Vec<u8> {
+        let payload = match (self, cipher) {
+            (Record::Batch(records), _) => {
+                let mut payload = vec![KIND_BATCH, 0];
+                for record in records {
+                    let inner_offset = offset + FRAME_HEADER_LEN + payload.len() as u64;
+                    payload.extend_from_slice(&record.encode(cipher, gen, inner_offset));
+                }
+                if let Some(cipher) = cipher {
+                    let end_offset = offset + FRAME_HEADER_LEN + payload.len() as u64;
+                    let end = batch_end(offset, records.len());
+                    payload.extend_from_slice(&frame(seal(cipher, &end, gen, end_offset)));
+                }
+                payload
+            }
+            (_, Some(cipher)) => seal(cipher, &self.payload(), gen, offset),
+            (_, None) => self.payload(),
+        };
+        frame(payload)
+    }
+
+    /// The unsealed payload of a set, remove or drop-tree record.
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Record::Set {
+                tree,
+                key,
+                value,
+                compressed,
+                expires_at,
+                seq,
+                timestamp,
+            } => {
+                payload.push(KIND_SET);
+                let mut flags = FLAG_VERSIONED | tree_flag(tree);
+                if expires_at.is_some() {
+                    flags |= FLAG_EXPIRES;
+                }
+                if *compressed {
+                    flags |= FLAG_COMPRESSED;
+                }
+                payload.push(flags);
+                payload.extend_from_slice(&seq.to_le_bytes());
+                payload.extend_from_slice(&timestamp.to_le_bytes());
+                if let Some(expires_at) = expires_at {
+                    payload.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                put_tree(&mut payload, tree);
+                put_bytes(&mut payload, key);
+                put_bytes(&mut payload, value);
+            }
+            Record::Remove {
+                tree,
+                key,
+                seq,
+                timestamp,
+            } => {
+                payload.push(KIND_REMOVE);
+                payload.push(FLAG_VERSIONED | tree_flag(tree));
+                payload.extend_from_slice(&seq.to_le_bytes());
+                payload.extend_from_slice(&timestamp.to_le_bytes());
+                put_tree(&mut payload, tree);
+                put_bytes(&mut payload, key);
+            }
+            Record::DropTree {
+                tree,
+                seq,
+                timestamp,
+            } => {
+                payload.push(KIND_DROP_TREE);
+                payload.push(FLAG_VERSIONED | FLAG_TREE);
+                payload.extend_from_slice(&seq.to_le_bytes());
+                payload.extend_from_slice(&timestamp.to_le_bytes());
+                put_bytes(&mut payload, tree.as_bytes());
+            }
+            Record::Batch(_) => unreachable!("batches are encoded frame by frame"),
+        }
+        payload
+    }
+
+    /// Give the record, or every record in a batch, consecutive sequence
+    /// numbers starting after `last_seq`, returning the last one used.
+    pub fn stamp(&mut self, last_seq: u64, now: u64) -> u64 {
+        match self {
+            Record::Set { seq, timestamp, .. }
+            | Record::Remove { seq, timestamp, .. }
+            | Record::DropTree { seq, timestamp, .. } => {
+                *seq = last_seq + 1;
+                *timestamp = now;
+                *seq
+            }
+            Record::Batch(records) => records
+                .iter_mut()
+                .fold(last_seq, |last_seq, record| record.stamp(last_seq, now)),
+        }
+    }
+
+    /// Compress the value of a set, or of every set in a batch, if it is at
+    /// least `min_size` bytes long and compressing makes it smaller. Values
+    /// that are already compressed are left alone.
+    pub fn compress(&mut self, compression: Compression, min_size: usize) {
+        match self {
+            Record::Set {
+                value, compressed, ..
+            } if !*compressed && value.len() >= min_size => {
+                if let Some(smaller) = compression::compress(compression, value) {
+                    *value = smaller;
+                    *compressed = true;
+                }
+            }
+            Record::Batch(records) => {
+                for record in records {
+                    record.compress(compression, min_size);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The value of a set, decompressed.
+    pub fn into_value(self) -> Result<Vec<u8>> {
+        match self {
+            Record::Set {
+                value,
+                compressed: false,
+                ..
+            } => Ok(value),
+            Record::Set { value, .. } => compression::decompress(&value),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Move the record, or every record in a batch, into keyspace `name`.
+    pub fn set_tree(&mut self, name: &str) {
+        match self {
+            Record::Set { tree, .. }
+            | Record::Remove { tree, .. }
+            | Record::DropTree { tree, .. } => *tree = name.to_owned(),
+            Record::Batch(records) => {
+                for record in records {
+                    record.set_tree(name);
+                }
+            }
+        }
+    }
+
+    /// Decode a complete frame, read from the log for `gen` at `offset`, as
+    /// produced by `encode` with `cipher`.
+    pub fn decode(
+        frame: &[u8],
+        cipher: Option<&Cipher>,
+        gen: u64,
+        offset: u64,
+    ) -> std::result::Result<Record, DecodeError> {
+        let payload = frame_payload(frame)?;
+        match (payload.first(), cipher) {
+            (Some(&KIND_BATCH), _) => decode_batch(&payload[1..], cipher, gen, offset),
+            (_, Some(cipher)) => {
+                decode_payload(&unseal(cipher, payload, gen, offset)?).ok_or(DecodeError::Corrupt)
+            }
+            (_, None) => decode_payload(payload).ok_or(DecodeError::Corrupt),
+        }
+    }
+}
+
+/// Frame `payload`, behind its checksum and length.
+fn frame(payload: Vec<u8>) -> Vec<u8> {
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN as usize + payload.len());
+    frame.extend_from_slice(&checksum(&len, &payload).to_le_bytes());
+    frame.extend_from_slice(&len);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// The payload of `frame`, if it is intact.
+fn frame_payload(frame: &[u8]) -> std::result::Result<&[u8], DecodeError> {
+    if frame.len() < FRAME_HEADER_LEN as usize {
+        return Err(DecodeError::Corrupt);
+    }
+    let (header, payload) = frame.split_at(FRAME_HEADER_LEN as usize);
+    let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let len = &header[4..8];
+    if u32::from_le_bytes(len.try_into().unwrap()) as usize != payload.len()
+        || checksum(len, payload) != crc
+    {
+        return Err(DecodeError::Corrupt);
+    }
+    Ok(payload)
+}
+
+/// `plaintext` sealed with `cipher` as the payload of a frame at `offset`
+/// in the log for `gen`.
+fn seal(cipher: &Cipher, plaintext: &[u8], gen: u64, offset: u64) -> Vec<u8> {
+    let mut payload = vec![KIND_SEALED];
+    let position = cipher.record_position(gen, offset);
+    payload.extend_from_slice(&cipher.seal(plaintext, &position));
+    payload
+}
+
+/// The plaintext of a payload `seal` produced for the same position.
+fn unseal(
+    cipher: &Cipher,
+    payload: &[u8],
+    gen: u64,
+    offset: u64,
+) -> std::result::Result<Vec<u8>, DecodeError> {
+    match payload.split_first() {
+        Some((&KIND_SEALED, sealed)) => cipher
+            .open(sealed, &cipher.record_position(gen, offset))
+            .ok_or(DecodeError::Tampered),
+        // The checksum can be forged, so this wasn't written by us
+        _ => Err(DecodeError::Tampered),
+    }
+}
+
+/// Plaintext of the frame that ends an encrypted batch at `offset` holding
+/// `count` records.
+fn batch_end(offset: u64, count: usize) -> Vec<u8> {
+    let mut payload = vec![KIND_BATCH_END, 0];
+    payload.extend_from_slice(&offset.to_le_bytes());
+    payload.extend_from_slice(&(count as u32).to_le_bytes());
+    payload
+}
+
+fn checksum(len: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(len);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Flag marking a record as belonging to `tree`; the default keyspace
+/// needs none.
+fn tree_flag(tree: &str) -> u8 {
+    if tree.is_empty() {
+        0
+    } else {
+        FLAG_TREE
+    }
+}
+
+fn put_tree(buf: &mut Vec<u8>, tree: &str) {
+    if !tree.is_empty() {
+        put_bytes(buf, tree.as_bytes());
+    }
+}
+
+fn take_u64(payload: &mut &[u8]) -> Option<u64> {
+    if payload.len() < 8 {
+        return None;
+    }
+    let value = u64::from_le_bytes(payload[0..8].try_into().unwrap());
+    *payload = &payload[8..];
+    Some(value)
+}
+
+fn take_bytes<'a>(payload: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if payload.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes(payload[0..4].try_into().unwrap()) as usize;
+    if payload.len() < 4 + len {
+        return None;
+    }
+    let bytes = &payload[4..4 + len];
+    *payload = &payload[4 + len..];
+    Some(bytes)
+}
+
+fn take_tree(payload: &mut &[u8], flags: u8) -> Option<String> {
+    if flags & FLAG_TREE == 0 {
+        return Some(String::new());
+    }
+    String::from_utf8(take_bytes(payload)?.to_vec()).ok()
+}
+
+/// The frames inside `frame`, if it is a batch that has been decoded, or
+/// nothing if it isn't a batch.
+pub fn batch_frames(frame: &[u8]) -> Vec<&[u8]> {
+    let mut frames = Vec::new();
+    if frame.get(FRAME_HEADER_LEN as usize) != Some(&KIND_BATCH) {
+        return frames;
+    }
+    let mut payload = &frame[BATCH_HEADER_LEN as usize..];
+    while payload.len() >= FRAME_HEADER_LEN as usize {
+        let len = u32::from_le_bytes(payload[4..8].try_into().unwrap()) as usize;
+        let (inner, rest) = payload.split_at((FRAME_HEADER_LEN as usize + len).min(payload.len()));
+        frames.push(inner);
+        payload = rest;
+    }
+    frames
+}
+
+/// Decode the payload of a batch found at `offset` in the log for `gen`,
+/// after its kind.
+fn decode_batch(
+    mut payload: &[u8],
+    cipher: Option<&Cipher>,
+    gen: u64,
+    offset: u64,
+) -> std::result::Result<Record, DecodeError> {
+    // Skip the flags
+    payload = payload.get(1..).ok_or(DecodeError::Corrupt)?;
+    let mut inner_offset = offset + BATCH_HEADER_LEN;
+    let mut frames = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < FRAME_HEADER_LEN as usize {
+            return Err(DecodeError::Corrupt);
+        }
+        let len = u32::from_le_bytes(payload[4..8].try_into().unwrap()) as usize;
+        let frame_len = FRAME_HEADER_LEN as usize + len;
+        if payload.len() < frame_len {
+            return Err(DecodeError::Corrupt);
+        }
+        frames.push((&payload[..frame_len], inner_offset));
+        payload = &payload[frame_len..];
+        inner_offset += frame_len as u64;
+    }
+    if let Some(cipher) = cipher {
+        let (end, end_offset) = frames.pop().ok_or(DecodeError::Tampered)?;
+        let end = unseal(cipher, frame_payload(end)?, gen, end_offset)?;
+        if end != batch_end(offset, frames.len()) {
+            return Err(DecodeError::Tampered);
+        }
+    }
+    let mut records = Vec::new();
+    for (frame, inner_offset) in frames {
+        match Record::decode(frame, cipher, gen, inner_offset)? {
+            // Batches don't nest
+            Record::Batch(_) => return Err(DecodeError::Corrupt),
+            record => records.push(record),
+        }
+    }
+    Ok(Record::Batch(records))
+}
+
+/// Decode the payload of a single set, remove or drop-tree record.
+fn decode_payload(mut payload: &[u8]) -> Option<Record> {
+    if payload.len() < 2 {
+        return None;
+    }
+    let kind = payload[0];
+    let flags = payload[1];
+    payload = &payload[2..];
+    let (seq, timestamp) = if flags & FLAG_VERSIONED != 0 {
+        (take_u64(&mut payload)?, take_u64(&mut payload)?)
+    } else {
+        (0, 0)
+    };
+    let record = match kind {
+        KIND_SET => {
+            let expires_at = if flags & FLAG_EXPIRES != 0 {
+                Some(take_u64(&mut payload)?)
+            } else {
+                None
+            };
+            Record::Set {
+                tree: take_tree(&mut payload, flags)?,
+                key: take_bytes(&mut payload)?.to_vec(),
+                value: take_bytes(&mut payload)?.to_vec(),
+                compressed: flags & FLAG_COMPRESSED != 0,
+                expires_at,
+                seq,
+                timestamp,
+            }
+        }
+        KIND_REMOVE => Record::Remove {
+            tree: take_tree(&mut payload, flags)?,
+            key: take_bytes(&mut payload)?.to_vec(),
+            seq,
+            timestamp,
+        },
+        // The default keyspace can't be dropped
+        KIND_DROP_TREE if flags & FLAG_TREE != 0 => Record::DropTree {
+            tree: take_tree(&mut payload, flags)?,
+            seq,
+            timestamp,
+        },
+        _ => return None,
+    };
+    if payload.is_empty() {
+        Some(record)
+    } else {
+        None
+    }
+}
+
+/// Write the file header for a new binary log, encrypted with key `key_id`
+/// or not at all if it is zero.
+pub fn write_header<W: Write>(writer: &mut W, key_id: u32) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&key_id.to_le_bytes())
+}
+
+/// Read the header of a log file, leaving `reader` at the first record.
+///
+/// An empty file is an unencrypted binary log whose header hasn't been
+/// written yet, and a file holding only part of a header is an
+/// `InvalidLogHeader` error.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<Header> {
+    let mut header = [0; HEADER_LEN as usize];
+    let read = read_fully(reader, &mut header[..OLD_HEADER_LEN as usize])?;
+    if read == 0 {
+        return Ok(Header {
+            format: LogFormat::Binary,
+            version: FORMAT_VERSION,
+            key_id: 0,
+            len: 0,
+        });
+    }
+    let magic_len = read.min(MAGIC.len());
+    if header[..magic_len] != MAGIC[..magic_len] {
+        return Ok(Header {
+            format: LogFormat::Json,
+            version: JSON_FORMAT_VERSION,
+            key_id: 0,
+            len: 0,
+        });
+    }
+    if read < OLD_HEADER_LEN as usize {
+        return Err(KvsError::InvalidLogHeader);
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedFormatVersion(version));
+    }
+    if version < 7 {
+        return Ok(Header {
+            format: LogFormat::Binary,
+            version,
+            key_id: 0,
+            len: OLD_HEADER_LEN,
+        });
+    }
+    if read_fully(reader, &mut header[OLD_HEADER_LEN as usize..])? < 4 {
+        return Err(KvsError::InvalidLogHeader);
+    }
+    Ok(Header {
+        format: LogFormat::Binary,
+        version,
+        key_id: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+        len: HEADER_LEN,
+    })
+}
+
+/// Read the next frame from a binary log.
+///
+/// Returns `None` at a clean end of file, and an error of kind
+/// `UnexpectedEof` if the file ends part way through a frame.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; FRAME_HEADER_LEN as usize];
+    match read_fully(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => {}
+    }
+    // Don't trust the length enough to allocate it up front; a corrupt
+    // header could claim gigabytes.
+    let len = u64::from(u32::from_le_bytes(header[4..8].try_into().unwrap()));
+    let mut frame = header.to_vec();
+    if (reader.take(len).read_to_end(&mut frame)? as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(frame))
+}
+
+/// Whether intact frames follow the damaged or incomplete one `tail` starts
+/// with, meaning it can't be a frame whose append was interrupted.
+///
+/// Only a run of intact frames that reaches the end of `tail` counts, as
+/// that is what the records after a damaged length look like. A frame that
+/// merely sits inside a value, which can be anything, doesn't. A batch whose
+/// append was interrupted holds whole inner frames, so those are skipped.
+pub fn intact_frames_follow(tail: &[u8]) -> bool {
+    let mut start = 1;
+    if tail.get(FRAME_HEADER_LEN as usize) == Some(&KIND_BATCH) {
+        start = BATCH_HEADER_LEN as usize;
+        while let Some(len) = tail.get(start..).and_then(intact_frame_len) {
+            start += len;
+        }
+    }
+    (start..tail.len()).any(|start| frames_reach_end(&tail[start..]))
+}
+
+/// Whether `bytes` are nothing but intact frames, back to back.
+fn frames_reach_end(mut bytes: &[u8]) -> bool {
+    while let Some(len) = intact_frame_len(bytes) {
+        bytes = &bytes[len..];
+        if bytes.is_empty() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Length of the frame `bytes` start with, if it is whole and passes its
+/// checksum.
+fn intact_frame_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < FRAME_HEADER_LEN as usize {
+        return None;
+    }
+    let crc = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let end = FRAME_HEADER_LEN as usize + len;
+    if len > 0 && end <= bytes.len() && checksum(&bytes[4..8], &bytes[8..end]) == crc {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
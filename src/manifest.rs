@@ -0,0 +1,160 @@
+//! The manifest of a `KvStore` directory.
+//!
+//! `MANIFEST` lists every generation whose log is part of the store, along
+//! with the format version of the oldest of those logs, the sequence number
+//! of the most recent write and the options the store was last opened with,
+//! as JSON. Opening and compacting only ever consider the logs
+//! it lists, so stray files that happen to look like logs are never loaded
+//! or deleted.
+//!
+//! The manifest is rewritten whole each time it changes: the new contents
+//! go to `MANIFEST.tmp`, which is synced and renamed over the old one before
+//! the directory is synced too. A crash leaves either the old manifest or
+//! the new one.
+//!
+//! A generation is listed before anything is written to its log, and a
+//! compacted one only once its log is complete, so that the logs a listed
+//! generation refers to are always either complete or the newest. Logs are
+//! only deleted after the manifest stops listing them.
+
+use crate::{KvStoreOptions, KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The live generations of a store, and what it was written with.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    dir: PathBuf,
+    contents: Contents,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Contents {
+    format_version: u32,
+    generations: BTreeSet<u64>,
+    /// At least the sequence number of the most recent write, which
+    /// compaction may have dropped from the logs. Missing from manifests
+    /// written before it was saved.
+    #[serde(default)]
+    last_seq: u64,
+    options: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Start a manifest for the store in `dir`. Nothing is written until
+    /// the first `save`.
+    pub(crate) fn new(
+        dir: &Path,
+        format_version: u32,
+        generations: BTreeSet<u64>,
+        last_seq: u64,
+        options: &KvStoreOptions,
+    ) -> Manifest {
+        Manifest {
+            dir: dir.to_owned(),
+            contents: Contents {
+                format_version,
+                generations,
+                last_seq,
+                options: options.describe(),
+            },
+        }
+    }
+
+    /// Read the manifest of the store in `dir`, or `None` if the store
+    /// predates manifests.
+    pub(crate) fn read(dir: &Path) -> Result<Option<Manifest>> {
+        let bytes = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let contents: Contents =
+            serde_json::from_slice(&bytes).map_err(|_| KvsError::CorruptManifest)?;
+        Ok(Some(Manifest {
+            dir: dir.to_owned(),
+            contents,
+        }))
+    }
+
+    /// Version of the log format of the oldest live log.
+    pub(crate) fn format_version(&self) -> u32 {
+        self.contents.format_version
+    }
+
+    /// The options the store was last opened with, by name.
+    pub(crate) fn options(&self) -> &BTreeMap<String, String> {
+        &self.contents.options
+    }
+
+    /// Sequence number the store's writes carry on from.
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.contents.last_seq
+    }
+
+    /// Every live generation, oldest first.
+    pub(crate) fn generations(&self) -> Vec<u64> {
+        self.contents.generations.iter().copied().collect()
+    }
+
+    /// Record that every live log is now in format `version`, and save.
+    pub(crate) fn set_format_version(&mut self, version: u32) -> Result<()> {
+        self.contents.format_version = version;
+        self.save()
+    }
+
+    /// Note that the store has made the write with sequence number `seq`,
+    /// to be saved with the next change.
+    pub(crate) fn saw_seq(&mut self, seq: u64) {
+        self.contents.last_seq = self.contents.last_seq.max(seq);
+    }
+
+    /// Change the live generations with `update` and save the result.
+    pub(crate) fn update(&mut self, update: impl FnOnce(&mut BTreeSet<u64>)) -> Result<()> {
+        update(&mut self.contents.generations);
+        self.save()
+    }
+
+    /// Atomically replace the manifest on disk with this one.
+    pub(crate) fn save(&self) -> Result<()> {
+        let mut contents = serde_json::to_vec_pretty(&self.contents)?;
+        contents.push(b'\n');
+        replace_file(&self.dir, MANIFEST_FILE, &contents)
+    }
+}
+
+/// Atomically replace the file `name` in `dir` with `contents`, by way of
+/// `temp_file_name(name)`.
+pub(crate) fn replace_file(dir: &Path, name: &str, contents: &[u8]) -> Result<()> {
+    let temp_path = dir.join(temp_file_name(name));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, dir.join(name))?;
+    sync_dir(dir)
+}
+
+/// Name of the file `replace_file` writes before renaming it to `name`.
+pub(crate) fn temp_file_name(name: &str) -> String {
+    format!("{}.tmp", name)
+}
+
+/// Make renames and deletions in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Make renames and deletions in `dir` durable. Windows can't open a
+/// directory to sync it, and makes renames durable with the file itself.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! The metadata file every store directory carries.
+//!
+//! `METADATA` records which engine created a directory, the on-disk format
+//! version it writes, when it was created and the options it was last opened
+//! with, as JSON. Both engines check it when opening, so a directory is never
+//! opened with the wrong engine, or by a version of kvs too old to read it.
+//!
+//! Directories written before the file existed may instead have the plain
+//! `engine` file `kvs-server` used to write, naming the engine. It is taken
+//! into account and replaced.
+
+use crate::kvsengine::now_millis;
+use crate::manifest::replace_file;
+use crate::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub(crate) const METADATA_FILE: &str = "METADATA";
+const LEGACY_ENGINE_FILE: &str = "engine";
+
+/// What is recorded about a store directory.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StoreMetadata {
+    /// Name of the engine that created the store: `kvs` or `sled`.
+    pub engine: String,
+    /// Version of the engine's on-disk format the store is written in.
+    pub format_version: u32,
+    /// When the store was created, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+    /// The options the store was last opened with, by name.
+    pub options: BTreeMap<String, String>,
+}
+
+impl StoreMetadata {
+    /// Read the metadata of the store in `dir`, or `None` if it has none.
+    pub fn read(dir: impl AsRef<Path>) -> Result<Option<StoreMetadata>> {
+        let bytes = match fs::read(dir.as_ref().join(METADATA_FILE)) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let metadata = serde_json::from_slice(&bytes).map_err(|_| KvsError::CorruptMetadata)?;
+        Ok(Some(metadata))
+    }
+
+    /// Check that the store in `dir` can be opened by `engine`, which writes
+    /// format `format_version`, without writing anything.
+    ///
+    /// Fails with `EngineMismatch` if another engine created the store, and
+    /// with `UnsupportedFormatVersion` if it is in a newer format.
+    pub(crate) fn check(
+        dir: &Path,
+        engine: &str,
+        format_version: u32,
+    ) -> Result<Option<StoreMetadata>> {
+        let metadata = StoreMetadata::read(dir)?;
+        let found = match &metadata {
+            Some(metadata) => Some(metadata.engine.clone()),
+            None => read_legacy_engine(dir)?,
+        };
+        if let Some(found) = found {
+            if found != engine {
+                return Err(KvsError::EngineMismatch {
+                    expected: engine.to_owned(),
+                    found,
+                });
+            }
+        }
+        match metadata {
+            Some(metadata) if metadata.format_version > format_version => {
+                Err(KvsError::UnsupportedFormatVersion(metadata.format_version))
+            }
+            metadata => Ok(metadata),
+        }
+    }
+
+    /// Record that `engine` has opened the store in `dir` with `options`,
+    /// and that it is now in format `format_version`, creating the metadata
+    /// if there is none. The store should have passed `check` first.
+    pub(crate) fn record(
+        dir: &Path,
+        engine: &str,
+        format_version: u32,
+        options: BTreeMap<String, String>,
+    ) -> Result<StoreMetadata> {
+        let metadata = match StoreMetadata::read(dir)? {
+            Some(metadata) => StoreMetadata {
+                format_version,
+                options,
+                ..metadata
+            },
+            None => StoreMetadata {
+                engine: engine.to_owned(),
+                format_version,
+                created_at: now_millis(),
+                options,
+            },
+        };
+        let mut contents = serde_json::to_vec_pretty(&metadata)?;
+        contents.push(b'\n');
+        replace_file(dir, METADATA_FILE, &contents)?;
+
+        let legacy_path = dir.join(LEGACY_ENGINE_FILE);
+        if legacy_path.exists() {
+            debug!("Replacing {:?} with {}", legacy_path, METADATA_FILE);
+            fs::remove_file(legacy_path)?;
+        }
+        Ok(metadata)
+    }
+}
+
+/// The engine named by the `engine` file `kvs-server` used to write, if
+/// there is one.
+fn read_legacy_engine(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(LEGACY_ENGINE_FILE)) {
+        Ok(engine) => Ok(Some(engine.trim().to_owned())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,92 @@
+//! Messages exchanged between `kvs-client` and `kvs-server`.
+//!
+//! Each connection carries one serde_json `Request` from the client and one
+//! `Response` back. Keys and values are sent as byte arrays, so they don't
+//! have to be UTF-8.
+//!
+//! A request can be wrapped in `Request::Namespaced` to pick the keyspace it
+//! applies to.
+
+use crate::KvsCommands;
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Request {
+    Get {
+        key: Vec<u8>,
+    },
+    /// Set a value, expiring after `ttl` if one is given.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetIfPresent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// `request`, made against the keyspace called `namespace` instead of
+    /// the one the server serves by default.
+    Namespaced {
+        namespace: String,
+        request: Box<Request>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Response {
+    /// Result of a `Get`; `None` if the key doesn't exist.
+    Value(Option<Vec<u8>>),
+    /// A `Set` or `Remove` succeeded.
+    Ok,
+    /// Whether a conditional write found its condition met and was applied.
+    Applied(bool),
+    /// The request failed, with the engine's error message.
+    Err(String),
+}
+
+impl From<KvsCommands> for Request {
+    fn from(command: KvsCommands) -> Self {
+        match command {
+            KvsCommands::Get { key } => Request::Get {
+                key: key.into_bytes(),
+            },
+            KvsCommands::Set { key, value, ttl } => Request::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                ttl: ttl.map(Duration::from_secs),
+            },
+            KvsCommands::Remove { key } => Request::Remove {
+                key: key.into_bytes(),
+            },
+            KvsCommands::CompareAndSwap { key, expected, new } => Request::CompareAndSwap {
+                key: key.into_bytes(),
+                expected: expected.map(String::into_bytes),
+                new: new.map(String::into_bytes),
+            },
+            KvsCommands::SetIfAbsent { key, value } => Request::SetIfAbsent {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+            },
+            KvsCommands::SetIfPresent { key, value } => Request::SetIfPresent {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+            },
+        }
+    }
+}
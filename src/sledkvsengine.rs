@@ -1,52 +1,550 @@
-use crate::{KvsEngine, KvsError, Result};
+use crate::durability::GroupCommit;
+use crate::kvsengine::{check_tree_name, expiry_time, is_empty_range, now_millis};
+use crate::kvsengine::{owned_bounds, time_left};
+use crate::logformat::Record;
+use crate::metadata::StoreMetadata;
+use crate::{Durability, KvsBytesIterator, KvsEngine, KvsError, KvsSnapshot, Result, WriteBatch};
 
-use sled::Db;
+use sled::{ConfigBuilder, Db, Tree};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::create_dir_all;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref, RangeBounds};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
 
+/// Name `SledKvsEngine` records in the metadata of the stores it creates.
+const ENGINE_NAME: &str = "sled";
+/// Version of the layout of keyspaces, expiry times and batches in sled.
+const FORMAT_VERSION: u32 = 1;
+
+/// Name of the tree holding expiry times for the default keyspace, keyed
+/// like it. Named keyspaces have their own, suffixed with `/` and the name.
+const EXPIRY_TREE: &str = "__kvs_expiry";
+/// Name of the tree holding batches that haven't been fully applied yet.
+const BATCH_TREE: &[u8] = b"__kvs_batches";
+/// Number of locks that writes to single keys are spread over.
+const KEY_LOCKS: usize = 64;
+
+/// A store backed by sled, in which every named keyspace is a sled `Tree`
+/// and the default keyspace is the database's default tree.
+#[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    /// Keyspace this handle reads and writes; empty for the default one.
+    tree: String,
+    /// Journal of batches being written, so that an interrupted batch can be
+    /// finished on the next open. sled 0.24 has no batch API of its own.
+    batches: Arc<Tree>,
+    /// Held shared by every write, and exclusively while a batch is applied,
+    /// a snapshot copies the tree or a keyspace is dropped.
+    writes: Arc<RwLock<()>>,
+    /// Held shared by every read, and exclusively while a batch is applied,
+    /// so that no read sees part of a batch.
+    reads: Arc<RwLock<()>>,
+    /// One of these is held while a key's value and expiry time are
+    /// written, so that writes to the same key can't interleave and leave
+    /// it with the value of one and the expiry time of another.
+    key_locks: Arc<Vec<Mutex<()>>>,
+    durability: Durability,
+    group: Arc<GroupCommit>,
+}
+
+/// The sled tree behind a keyspace.
+enum Space {
+    Default(Db),
+    Named(Arc<Tree>),
+}
+
+impl Deref for Space {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        match self {
+            Space::Default(db) => db,
+            Space::Named(tree) => tree,
+        }
+    }
 }
 
 impl SledKvsEngine {
+    /// Open the database at `pathbuf`, finishing any batches that were
+    /// interrupted and removing any keys that expired while it was closed.
     pub fn open(pathbuf: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        Ok(SledKvsEngine {
-            db: Db::start_default(&pathbuf.into())?,
-        })
+        Self::open_with_durability(pathbuf, Durability::default())
+    }
+
+    /// Open the database at `pathbuf`, syncing writes as `durability` asks.
+    ///
+    /// `Durability::Periodic` sets how often sled's own background flusher
+    /// runs; with `Durability::None` it runs at sled's default interval.
+    pub fn open_with_durability(
+        pathbuf: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<SledKvsEngine> {
+        let path = pathbuf.into();
+        create_dir_all(&path)?;
+        StoreMetadata::check(&path, ENGINE_NAME, FORMAT_VERSION)?;
+        let mut config = ConfigBuilder::new().path(&path);
+        if let Durability::Periodic(interval) = durability {
+            config = config.flush_every_ms(Some(interval.as_millis().max(1) as u64));
+        }
+        let db = Db::start(config.build())?;
+        // Only recorded once sled has the database, and so the directory
+        let mut options = BTreeMap::new();
+        options.insert("durability".to_owned(), durability.to_string());
+        StoreMetadata::record(&path, ENGINE_NAME, FORMAT_VERSION, options)?;
+        let batches = db.open_tree(BATCH_TREE)?;
+        let engine = SledKvsEngine {
+            db,
+            tree: String::new(),
+            batches,
+            writes: Arc::new(RwLock::new(())),
+            reads: Arc::new(RwLock::new(())),
+            key_locks: Arc::new((0..KEY_LOCKS).map(|_| Mutex::new(())).collect()),
+            durability,
+            group: Arc::new(GroupCommit::default()),
+        };
+        engine.replay_batches()?;
+        engine.remove_expired()?;
+        Ok(engine)
+    }
+
+    /// Flush the write just made to disk, if the durability setting asks for
+    /// it before a write is acknowledged.
+    fn commit(&self) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite => {
+                self.db.flush()?;
+            }
+            Durability::GroupCommit => {
+                self.group.wrote();
+                self.group.commit(|| {
+                    self.db.flush()?;
+                    Ok(())
+                })?;
+            }
+            Durability::None | Durability::Periodic(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Handle on keyspace `name`, which is assumed to be valid.
+    fn handle(&self, name: &str) -> SledKvsEngine {
+        SledKvsEngine {
+            tree: name.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    /// The tree holding this handle's keyspace.
+    ///
+    /// Named trees are looked up on every use rather than kept, so that a
+    /// handle carries on working after its keyspace is dropped.
+    fn space(&self) -> Result<Space> {
+        if self.tree.is_empty() {
+            Ok(Space::Default(self.db.clone()))
+        } else {
+            Ok(Space::Named(self.db.open_tree(&self.tree)?))
+        }
+    }
+
+    /// Lock out other writes to `key` in this handle's keyspace.
+    fn lock_key(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        (&self.tree, key).hash(&mut hasher);
+        self.key_locks[hasher.finish() as usize % KEY_LOCKS]
+            .lock()
+            .unwrap()
+    }
+
+    /// Expiry time, in milliseconds since the Unix epoch, of every key in
+    /// this handle's keyspace that has one.
+    fn expiry(&self) -> Result<Arc<Tree>> {
+        Ok(self.db.open_tree(expiry_tree(&self.tree))?)
+    }
+
+    /// Apply the batches left in the journal by a previous run.
+    ///
+    /// Every operation in a batch is idempotent, so it doesn't matter how
+    /// much of it was applied before.
+    fn replay_batches(&self) -> Result<()> {
+        for item in self.batches.iter() {
+            let (id, encoded) = item?;
+            match Record::decode(&encoded, None, 0, 0) {
+                Ok(Record::Batch(records)) => {
+                    warn!(
+                        "Finishing interrupted batch of {} operations",
+                        records.len()
+                    );
+                    self.apply_batch(records)?;
+                }
+                _ => error!("Discarding unreadable batch from journal"),
+            }
+            self.batches.del(&id)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn apply_batch(&self, records: Vec<Record>) -> Result<()> {
+        for record in records {
+            match record {
+                Record::Set {
+                    tree, key, value, ..
+                } => {
+                    let handle = self.handle(&tree);
+                    handle.expiry()?.del(&key)?;
+                    handle.space()?.set(key, value)?;
+                }
+                Record::Remove { tree, key, .. } => {
+                    let handle = self.handle(&tree);
+                    handle.expiry()?.del(&key)?;
+                    handle.space()?.del(&key)?;
+                }
+                Record::Batch(_) | Record::DropTree { .. } => {
+                    unreachable!("batches only hold sets and removes")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expires_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self
+            .expiry()?
+            .get(key)?
+            .map(|bytes| u64::from_be_bytes(bytes.as_ref().try_into().unwrap_or([0; 8]))))
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .expires_at(key)?
+            .is_some_and(|expires_at| expires_at <= now_millis()))
+    }
+
+    /// Remove the expired keys from every keyspace.
+    fn remove_expired(&self) -> Result<()> {
+        let now = now_millis();
+        let names = self.db.tree_names();
+        let names = names
+            .iter()
+            .filter_map(|name| keyspace_of_expiry_tree(name));
+        for handle in names.map(|name| self.handle(name)).collect::<Vec<_>>() {
+            let (space, expiry) = (handle.space()?, handle.expiry()?);
+            for item in expiry.iter() {
+                let (key, expires_at) = item?;
+                if u64::from_be_bytes(expires_at.as_ref().try_into().unwrap_or([0; 8])) <= now {
+                    debug!("Removing expired key {}", String::from_utf8_lossy(&key));
+                    space.del(&key)?;
+                    expiry.del(&key)?;
+                }
+            }
+        }
+        self.db.flush()?;
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
+    type Snapshot = SledSnapshot;
+
     #[logfn(Trace)]
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .db
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _reading = self.reads.read().unwrap();
+        if self.is_expired(key)? {
+            return Ok(None);
+        }
+        Ok(self.space()?.get(key)?.map(|i_vec| i_vec.to_vec()))
     }
 
     #[logfn(Trace)]
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.set(key.clone(), value.as_bytes())?;
-        self.db.flush()?;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _writing = self.writes.read().unwrap();
+        let _key = self.lock_key(&key);
+        self.expiry()?.del(&key)?;
+        self.space()?.set(key, value)?;
+        self.commit()?;
         Ok(())
     }
 
     #[logfn(Trace)]
-    fn remove(&mut self, key: String) -> Result<()> {
-        let ret = match self.db.del(key)? {
-            Some(_) => {
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let _writing = self.writes.read().unwrap();
+        let _key = self.lock_key(key);
+        let expired = self.is_expired(key)?;
+        self.expiry()?.del(key)?;
+        let ret = match self.space()?.del(key)? {
+            Some(_) if !expired => {
                 debug!("remove, found previous value");
                 Ok(())
             }
-            None => {
+            _ => {
                 debug!("remove, no previous value found");
                 Err(KvsError::KeyNotFound)
             }
         };
-        self.db.flush()?;
+        self.commit()?;
 
         ret
     }
+
+    #[logfn(Trace)]
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _writing = self.writes.read().unwrap();
+        let _key = self.lock_key(&key);
+        self.expiry()?
+            .set(&key, expiry_time(ttl).to_be_bytes().to_vec())?;
+        self.space()?.set(key, value)?;
+        self.commit()?;
+        Ok(())
+    }
+
+    #[logfn(Trace)]
+    fn expire_bytes(&self, key: &[u8], ttl: Duration) -> Result<()> {
+        let _writing = self.writes.read().unwrap();
+        let _key = self.lock_key(key);
+        if self.is_expired(key)? || !self.space()?.contains_key(key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.expiry()?
+            .set(key, expiry_time(ttl).to_be_bytes().to_vec())?;
+        self.commit()?;
+        Ok(())
+    }
+
+    #[logfn(Trace)]
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let _reading = self.reads.read().unwrap();
+        if self.is_expired(key)? || !self.space()?.contains_key(key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        Ok(self.expires_at(key)?.map(time_left))
+    }
+
+    #[logfn(Trace)]
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let _writing = self.writes.read().unwrap();
+        let _key = self.lock_key(&key);
+        if self.is_expired(&key)? {
+            // An expired value must not match, so clear it out first
+            if let Some(old) = self.space()?.get(&key)? {
+                let _ = self.space()?.cas(&key, Some(old), None::<Vec<u8>>)?;
+            }
+            self.expiry()?.del(&key)?;
+        }
+        let swapped = self.space()?.cas(&key, expected, new)?.is_ok();
+        if swapped {
+            self.expiry()?.del(&key)?;
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Journal the batch in a single write, then apply it while reads and
+    /// other writes wait.
+    #[logfn(Trace)]
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _writing = self.writes.write().unwrap();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let id = self.db.generate_id()?.to_be_bytes();
+        let records = batch.into_records(&self.tree);
+        // Journal entries aren't sealed, so their position doesn't matter
+        self.batches
+            .set(id, Record::Batch(records.clone()).encode(None, 0, 0))?;
+        self.db.flush()?;
+        {
+            let _applying = self.reads.write().unwrap();
+            self.apply_batch(records)?;
+        }
+        self.batches.del(id)?;
+        self.commit()?;
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        let (next, end) = owned_bounds(&range);
+        Ok(Box::new(SledIter {
+            engine: self.clone(),
+            next,
+            end,
+            done: false,
+        }))
+    }
+
+    /// sled 0.24 has neither snapshots nor consistent iteration, so this
+    /// copies every live key and value of the keyspace into memory.
+    ///
+    /// That is a known limitation: it takes memory and time in proportion to
+    /// the keyspace, and writes wait until the copy is done. Reads carry on.
+    #[logfn(Trace)]
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let _copying = self.writes.write().unwrap();
+        let now = now_millis();
+        let mut data = BTreeMap::new();
+        for item in self.space()?.iter() {
+            let (key, value) = item?;
+            let expired = self
+                .expires_at(&key)?
+                .is_some_and(|expires_at| expires_at <= now);
+            if !expired {
+                data.insert(key, value.to_vec());
+            }
+        }
+        Ok(SledSnapshot {
+            data: Arc::new(data),
+        })
+    }
+
+    fn open_tree(&self, name: &str) -> Result<SledKvsEngine> {
+        check_tree_name(name)?;
+        Ok(self.handle(name))
+    }
+
+    /// Reading through a handle creates its sled tree, so empty trees are
+    /// left out.
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            let name = match String::from_utf8(name) {
+                Ok(name) if !name.starts_with("__") => name,
+                _ => continue,
+            };
+            if !self.db.open_tree(&name)?.is_empty() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    #[logfn(Trace)]
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        check_tree_name(name)?;
+        let _dropping = self.writes.write().unwrap();
+        let existed = self.tree_names()?.iter().any(|existing| existing == name);
+        self.db.drop_tree(name.as_bytes())?;
+        self.db.drop_tree(expiry_tree(name).as_bytes())?;
+        self.db.flush()?;
+        Ok(existed)
+    }
+}
+
+/// Name of the tree holding expiry times for keyspace `name`.
+fn expiry_tree(name: &str) -> String {
+    if name.is_empty() {
+        EXPIRY_TREE.to_owned()
+    } else {
+        format!("{}/{}", EXPIRY_TREE, name)
+    }
+}
+
+/// Keyspace whose expiry times are kept in the tree called `name`, if it is
+/// an expiry tree.
+fn keyspace_of_expiry_tree(name: &[u8]) -> Option<&str> {
+    let name = std::str::from_utf8(name).ok()?;
+    match name.strip_prefix(EXPIRY_TREE)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+/// Walks a sled tree in key order, restarting the range after the last key
+/// returned on every step so the iterator doesn't borrow the tree.
+struct SledIter {
+    engine: SledKvsEngine,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    done: bool,
+}
+
+impl Iterator for SledIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done || is_empty_range(&self.next, &self.end) {
+                return None;
+            }
+            let space = match self.engine.space() {
+                Ok(space) => space,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            let reads = self.engine.reads.clone();
+            let _reading = reads.read().unwrap();
+            let item = space
+                .range::<Vec<u8>, _>((self.next.clone(), self.end.clone()))
+                .next()?;
+            let result = item.map_err(Into::into).and_then(|(key, value)| {
+                self.next = Bound::Excluded(key.clone());
+                Ok((self.engine.is_expired(&key)?, key, value))
+            });
+            match result {
+                Ok((true, _, _)) => continue,
+                Ok((false, key, value)) => return Some(Ok((key, value.to_vec()))),
+                Err(e) => {
+                    // Stop after reporting the error rather than retrying forever
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// A copy of a `SledKvsEngine` keyspace as it was when `snapshot` was
+/// called, held in memory.
+#[derive(Clone, Debug)]
+pub struct SledSnapshot {
+    data: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvsBytesIterator> {
+        let (next, end) = owned_bounds(&range);
+        Ok(Box::new(SledSnapshotIter {
+            data: self.data.clone(),
+            next,
+            end,
+        }))
+    }
+}
+
+struct SledSnapshotIter {
+    data: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for SledSnapshotIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if is_empty_range(&self.next, &self.end) {
+            return None;
+        }
+        let (key, value) = self
+            .data
+            .range((self.next.clone(), self.end.clone()))
+            .next()?;
+        self.next = Bound::Excluded(key.clone());
+        Some(Ok((key.clone(), value.clone())))
+    }
 }
@@ -0,0 +1,122 @@
+use crate::{KvsError, Result};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::thread;
+
+/// A pool of threads that jobs can be handed off to.
+pub trait ThreadPool: Sized {
+    /// Create a pool with `threads` worker threads. Fails with `NoThreads`
+    /// if `threads` is zero, as nothing would ever run.
+    fn new(threads: u32) -> Result<Self>;
+
+    /// Run `job` on one of the pool's threads.
+    ///
+    /// A panicking job must not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// Spawns a new thread for every job; `threads` is only checked.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvsError::NoThreads);
+    }
+    Ok(())
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of workers pulling jobs off a shared channel.
+///
+/// A worker whose job panics is replaced by a fresh one, so the pool keeps
+/// its size. Dropping the pool closes the channel and the workers exit once
+/// the queue is drained.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+        let (sender, receiver) = unbounded::<Job>();
+        for _ in 0..threads {
+            let worker = Worker(receiver.clone());
+            thread::Builder::new().spawn(move || worker.run())?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("No worker threads left in the pool");
+    }
+}
+
+#[derive(Clone)]
+struct Worker(Receiver<Job>);
+
+impl Worker {
+    fn run(self) {
+        while let Ok(job) = self.0.recv() {
+            job();
+        }
+        debug!("Worker exiting, job queue closed");
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!("Job panicked, starting a replacement worker");
+            let worker = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || worker.run()) {
+                error!("Failed to start replacement worker: {}", e);
+            }
+        }
+    }
+}
+
+/// Wrapper around a `rayon` thread pool.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        // rayon would take zero to mean one thread per CPU
+        check_threads(threads)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| warn!("Job panicked in rayon thread pool"))
+            .build()?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
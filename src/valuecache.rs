@@ -0,0 +1,199 @@
+//! A size-bounded cache of values read from the log.
+//!
+//! Values are cached by where their record sits in the log. A record never
+//! changes once written, so an entry can't go stale; entries are only
+//! dropped to free space, when the key they belong to is overwritten or
+//! removed, and when compaction moves or deletes the record.
+//!
+//! Eviction uses the CLOCK algorithm: a hit only sets a flag on the entry,
+//! so concurrent readers share a read lock, and the hand sweeping for an
+//! entry to evict gives flagged entries a second chance.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Generation and offset of a record.
+type Position = (u64, u64);
+
+/// How well a `KvStore`'s value cache is doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads answered from the cache.
+    pub hits: u64,
+    /// Reads that had to go to the log.
+    pub misses: u64,
+    /// Number of values cached.
+    pub entries: usize,
+    /// Total size of the values cached.
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    /// Most bytes of values to hold. Zero turns the cache off.
+    capacity: u64,
+    clock: RwLock<Clock>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Clock {
+    slots: Vec<Option<Slot>>,
+    positions: HashMap<Position, usize>,
+    /// Empty slots, for reuse.
+    free: Vec<usize>,
+    hand: usize,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Slot {
+    position: Position,
+    value: Vec<u8>,
+    /// Set on every hit, and cleared as the hand passes.
+    referenced: AtomicBool,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            clock: RwLock::new(Clock::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The value of the record at `offset` in `gen`, if it is cached.
+    pub(crate) fn get(&self, gen: u64, offset: u64) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let clock = self.clock.read().unwrap();
+        let slot = clock
+            .positions
+            .get(&(gen, offset))
+            .and_then(|&index| clock.slots[index].as_ref());
+        match slot {
+            Some(slot) => {
+                slot.referenced.store(true, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(slot.value.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Cache `value` as that of the record at `offset` in `gen`, evicting
+    /// others to make room. Values bigger than the whole cache aren't kept.
+    pub(crate) fn insert(&self, gen: u64, offset: u64, value: &[u8]) {
+        let len = value.len() as u64;
+        if self.capacity == 0 || len > self.capacity {
+            return;
+        }
+        let mut clock = self.clock.write().unwrap();
+        if clock.positions.contains_key(&(gen, offset)) {
+            return;
+        }
+        while clock.bytes + len > self.capacity {
+            clock.evict_one();
+        }
+        clock.bytes += len;
+        let slot = Slot {
+            position: (gen, offset),
+            value: value.to_vec(),
+            referenced: AtomicBool::new(false),
+        };
+        let index = match clock.free.pop() {
+            Some(index) => {
+                clock.slots[index] = Some(slot);
+                index
+            }
+            None => {
+                clock.slots.push(Some(slot));
+                clock.slots.len() - 1
+            }
+        };
+        clock.positions.insert((gen, offset), index);
+    }
+
+    /// Drop the value of the record at `offset` in `gen`.
+    pub(crate) fn remove(&self, gen: u64, offset: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut clock = self.clock.write().unwrap();
+        if let Some(index) = clock.positions.get(&(gen, offset)).copied() {
+            clock.free_slot(index);
+        }
+    }
+
+    /// Note that compaction copied the record at `old` to `new`.
+    pub(crate) fn remap(&self, old: Position, new: Position) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut clock = self.clock.write().unwrap();
+        if let Some(index) = clock.positions.remove(&old) {
+            clock.slots[index].as_mut().unwrap().position = new;
+            clock.positions.insert(new, index);
+        }
+    }
+
+    /// Drop the values of every record in generations before `gen`.
+    pub(crate) fn retain_from(&self, gen: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut clock = self.clock.write().unwrap();
+        let stale: Vec<usize> = clock
+            .positions
+            .iter()
+            .filter(|((slot_gen, _), _)| *slot_gen < gen)
+            .map(|(_, index)| *index)
+            .collect();
+        for index in stale {
+            clock.free_slot(index);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let clock = self.clock.read().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: clock.positions.len(),
+            bytes: clock.bytes,
+        }
+    }
+}
+
+impl Clock {
+    /// Move the hand on until it finds an entry that hasn't been hit since
+    /// it last passed, and evict that.
+    fn evict_one(&mut self) {
+        loop {
+            let index = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            if let Some(slot) = &mut self.slots[index] {
+                if !std::mem::replace(slot.referenced.get_mut(), false) {
+                    self.free_slot(index);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn free_slot(&mut self, index: usize) {
+        if let Some(slot) = self.slots[index].take() {
+            self.positions.remove(&slot.position);
+            self.bytes -= slot.value.len() as u64;
+            self.free.push(index);
+        }
+    }
+}
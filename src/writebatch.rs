@@ -0,0 +1,73 @@
+use crate::logformat::Record;
+
+/// A group of sets and removes that are applied all together or not at all.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+/// # fn main() -> Result<()> {
+/// let store = KvStore::open("/var/lib/kvs")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("from", "90");
+/// batch.set("to", "110");
+/// batch.remove("transfer");
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A batch applies to the keyspace of the handle it is written through.
+/// Operations are applied in the order they were added. Unlike
+/// `KvsEngine::remove`, removing a key that doesn't exist is not an error,
+/// so a batch never fails part way through because of its contents.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) records: Vec<Record>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `key` to `value` when the batch is written.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.records.push(Record::Set {
+            tree: String::new(),
+            key: key.into(),
+            value: value.into(),
+            compressed: false,
+            expires_at: None,
+            seq: 0,
+            timestamp: 0,
+        });
+        self
+    }
+
+    /// Remove `key` when the batch is written.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.records.push(Record::Remove {
+            tree: String::new(),
+            key: key.into(),
+            seq: 0,
+            timestamp: 0,
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The operations, as records in keyspace `tree`.
+    pub(crate) fn into_records(self, tree: &str) -> Vec<Record> {
+        let mut records = self.records;
+        for record in &mut records {
+            record.set_tree(tree);
+        }
+        records
+    }
+}
@@ -16,6 +16,7 @@ fn client_cli_no_args() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -48,6 +49,7 @@ fn client_cli_invalid_get() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -87,6 +89,7 @@ fn client_cli_invalid_set() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -119,6 +122,7 @@ fn client_cli_invalid_rm() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -131,6 +135,7 @@ fn client_cli_invalid_subcommand() {
 
 // `kvs-client -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
@@ -142,6 +147,7 @@ fn client_cli_version() {
 
 // `kvs-server -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
@@ -152,6 +158,75 @@ fn server_cli_version() {
 }
 
 #[test]
+fn server_cli_invalid_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--pool", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "many"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("at least 1"));
+}
+
+#[test]
+fn server_cli_invalid_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--durability", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("every-write"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--durability", "periodic:soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_invalid_key_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("keys");
+    fs::write(&key_file, format!("0:{}\n", "ab".repeat(32))).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--key-file", key_file.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("reserved"));
+
+    fs::write(&key_file, "# no keys yet\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--key-file", key_file.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("no keys"));
+}
+
+#[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
@@ -164,6 +239,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,6 +248,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -184,6 +261,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +281,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,6 +291,7 @@ fn cli_wrong_engine() {
     }
 }
 
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +304,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -291,6 +372,113 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-absent", "key2", "value5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition not met"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key2",
+            "--expected",
+            "value3",
+            "--new",
+            "value6",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key2",
+            "--expected",
+            "value3",
+            "--new",
+            "value7",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition not met"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key2",
+            "--expected",
+            "value6",
+            "--new",
+            "value3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key3", "value4", "--ttl", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--namespace",
+            "users",
+            "set",
+            "key2",
+            "user2",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--namespace", "users", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--namespace", "__kvs", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid keyspace name"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -305,6 +493,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -315,6 +504,13 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("value3"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--namespace", "users", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("user2"));
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["--addr", addr, "get", "key1"])
@@ -322,6 +518,13 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +538,39 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn admin_cli_upgrade() {
+    let temp_dir = TempDir::new().unwrap();
+    let legacy = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    fs::write(temp_dir.path().join("1.log"), legacy).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["upgrade", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Would rewrite generation 1 (format version 0)"));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("1.log")).unwrap(),
+        legacy
+    );
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["upgrade", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Rewrote generation 1"));
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["upgrade", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Already at format version"));
+}
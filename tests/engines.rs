@@ -0,0 +1,650 @@
+use kvs::{
+    Durability, KvStore, KvsEngine, KvsError, KvsSnapshot, Result, SledKvsEngine, StoreMetadata,
+    WriteBatch,
+};
+use std::fs;
+use std::ops::Bound;
+use std::panic;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn populate<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in &["b", "a", "ab", "abc", "c", "d"] {
+        engine.set(key.to_string(), format!("value-{}", key))?;
+    }
+    Ok(())
+}
+
+fn keys(iter: kvs::KvsIterator) -> Result<Vec<String>> {
+    iter.map(|item| item.map(|(key, _)| key)).collect()
+}
+
+fn scan_in_key_order<E: KvsEngine>(engine: E) -> Result<()> {
+    populate(&engine)?;
+
+    let all: Vec<(String, String)> = engine.scan(..)?.collect::<Result<_>>()?;
+    assert_eq!(
+        all,
+        vec![
+            ("a".to_owned(), "value-a".to_owned()),
+            ("ab".to_owned(), "value-ab".to_owned()),
+            ("abc".to_owned(), "value-abc".to_owned()),
+            ("b".to_owned(), "value-b".to_owned()),
+            ("c".to_owned(), "value-c".to_owned()),
+            ("d".to_owned(), "value-d".to_owned()),
+        ]
+    );
+
+    assert_eq!(
+        keys(engine.scan("ab".to_owned().."c".to_owned())?)?,
+        vec!["ab", "abc", "b"]
+    );
+    assert_eq!(
+        keys(engine.scan((
+            Bound::Excluded("b".to_owned()),
+            Bound::Included("d".to_owned())
+        ))?)?,
+        vec!["c", "d"]
+    );
+    assert!(keys(engine.scan("d".to_owned().."a".to_owned())?)?.is_empty());
+
+    engine.remove("b".to_owned())?;
+    assert_eq!(keys(engine.scan("b".to_owned()..)?)?, vec!["c", "d"]);
+
+    Ok(())
+}
+
+fn scan_prefix<E: KvsEngine>(engine: E) -> Result<()> {
+    populate(&engine)?;
+
+    assert_eq!(
+        keys(engine.scan_prefix("a".to_owned())?)?,
+        vec!["a", "ab", "abc"]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("ab".to_owned())?)?,
+        vec!["ab", "abc"]
+    );
+    assert!(keys(engine.scan_prefix("x".to_owned())?)?.is_empty());
+
+    Ok(())
+}
+
+fn binary_keys_and_values<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0, 159, 146, 150, 255];
+    let value = vec![255, 254, 0, 1, 2];
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(vec![0], vec![])?;
+
+    assert_eq!(engine.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(engine.get_bytes(&[0])?, Some(vec![]));
+    assert_eq!(engine.get_bytes(&[1])?, None);
+
+    let all: Vec<(Vec<u8>, Vec<u8>)> = engine.scan_prefix_bytes(vec![0])?.collect::<Result<_>>()?;
+    assert_eq!(all, vec![(vec![0], vec![]), (key.clone(), value)]);
+
+    // The string methods refuse to decode data that isn't UTF-8
+    engine.set_bytes(b"key".to_vec(), vec![0xff])?;
+    match engine.get("key".to_owned()) {
+        Err(KvsError::UTF8Error(_)) => {}
+        other => panic!("expected UTF-8 error, got {:?}", other),
+    }
+
+    engine.remove_bytes(&key)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    assert!(engine.remove_bytes(&key).is_err());
+
+    Ok(())
+}
+
+fn expiring_keys<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl("long".to_owned(), "2".to_owned(), Duration::from_secs(3600))?;
+    engine.set("forever".to_owned(), "3".to_owned())?;
+    engine.set("later".to_owned(), "4".to_owned())?;
+    engine.expire("later".to_owned(), Duration::from_millis(200))?;
+
+    assert_eq!(engine.get("short".to_owned())?, Some("1".to_owned()));
+    assert!(engine.ttl("long".to_owned())?.unwrap() > Duration::from_secs(3500));
+    assert_eq!(engine.ttl("forever".to_owned())?, None);
+    match engine.expire("missing".to_owned(), Duration::from_secs(1)) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.get("later".to_owned())?, None);
+    assert_eq!(engine.get("long".to_owned())?, Some("2".to_owned()));
+    assert_eq!(keys(engine.scan(..)?)?, vec!["forever", "long"]);
+    assert!(engine.ttl("short".to_owned()).is_err());
+    assert!(engine.remove("short".to_owned()).is_err());
+
+    // A plain set clears the expiry
+    engine.set("long".to_owned(), "5".to_owned())?;
+    assert_eq!(engine.ttl("long".to_owned())?, None);
+
+    Ok(())
+}
+
+fn write_batch<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.set_with_ttl("c".to_owned(), "3".to_owned(), Duration::from_secs(3600))?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("a", "10")
+        .remove("b")
+        .remove("missing")
+        .set("c", "30")
+        .set("d", "40")
+        .set(vec![0xff], vec![0]);
+    assert_eq!(batch.len(), 6);
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    assert_eq!(engine.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(engine.get("b".to_owned())?, None);
+    assert_eq!(engine.get("c".to_owned())?, Some("30".to_owned()));
+    assert_eq!(engine.ttl("c".to_owned())?, None);
+    assert_eq!(engine.get("d".to_owned())?, Some("40".to_owned()));
+    assert_eq!(engine.get_bytes(&[0xff])?, Some(vec![0]));
+
+    Ok(())
+}
+
+#[test]
+fn kvs_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("d".to_owned())?, Some("40".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A reader that sees one key of a batch must see the rest of it too, so "b"
+// is never behind "a" when read after it
+fn batch_is_atomic_to_readers<E: KvsEngine>(engine: E) -> Result<()> {
+    let number = |value: Option<String>| value.map_or(0, |value| value.parse::<u32>().unwrap());
+    let reader = {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            loop {
+                let a = number(engine.get("a".to_owned())?);
+                let b = number(engine.get("b".to_owned())?);
+                assert!(b >= a, "read a = {} but then b = {}", a, b);
+                if a == 200 {
+                    return Ok(());
+                }
+            }
+        })
+    };
+    for i in 1..=200 {
+        let mut batch = WriteBatch::new();
+        batch.set("a", i.to_string()).set("b", i.to_string());
+        engine.write_batch(batch)?;
+    }
+    reader.join().unwrap()
+}
+
+#[test]
+fn kvs_batch_is_atomic_to_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    batch_is_atomic_to_readers(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_batch_is_atomic_to_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    batch_is_atomic_to_readers(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Racing writes to one key leave it with the value and the expiry time of
+// the same write
+fn racing_writes_keep_expiry<E: KvsEngine>(engine: E) -> Result<()> {
+    for _ in 0..200 {
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let engine = engine.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..5 {
+                        if writer % 2 == 0 {
+                            engine.set_with_ttl(
+                                "key".to_owned(),
+                                "expiring".to_owned(),
+                                Duration::from_secs(3600),
+                            )?;
+                        } else {
+                            engine.set("key".to_owned(), "lasting".to_owned())?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        let value = engine.get("key".to_owned())?.unwrap();
+        let ttl = engine.ttl("key".to_owned())?;
+        assert_eq!(
+            ttl.is_some(),
+            value == "expiring",
+            "{} with ttl {:?}",
+            value,
+            ttl
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_racing_writes_keep_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_writes_keep_expiry(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_racing_writes_keep_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_writes_keep_expiry(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn compare_and_swap<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || "key".to_owned();
+
+    assert!(engine.compare_and_swap(key(), None, Some("1".to_owned()))?);
+    assert!(!engine.compare_and_swap(key(), None, Some("2".to_owned()))?);
+    assert!(!engine.compare_and_swap(key(), Some("0".to_owned()), Some("2".to_owned()))?);
+    assert!(engine.compare_and_swap(key(), Some("1".to_owned()), Some("2".to_owned()))?);
+    assert_eq!(engine.get(key())?, Some("2".to_owned()));
+    assert!(engine.compare_and_swap(key(), Some("2".to_owned()), None)?);
+    assert_eq!(engine.get(key())?, None);
+
+    assert!(!engine.set_if_present(key(), "3".to_owned())?);
+    assert!(engine.set_if_absent(key(), "3".to_owned())?);
+    assert!(!engine.set_if_absent(key(), "4".to_owned())?);
+    assert!(engine.set_if_present(key(), "4".to_owned())?);
+    assert_eq!(engine.get(key())?, Some("4".to_owned()));
+
+    // An expired value counts as missing
+    engine.set_with_ttl(key(), "5".to_owned(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(!engine.compare_and_swap(key(), Some("5".to_owned()), None)?);
+    assert!(engine.set_if_absent(key(), "6".to_owned())?);
+    assert_eq!(engine.ttl(key())?, None);
+
+    // Racing increments must not lose updates
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    loop {
+                        let current = engine.get("counter".to_owned()).unwrap();
+                        let next = current.as_ref().map_or(0, |n| n.parse::<u32>().unwrap()) + 1;
+                        if engine
+                            .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("100".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn kvs_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn snapshot<E: KvsEngine>(engine: E) -> Result<()> {
+    populate(&engine)?;
+    let snapshot = engine.snapshot()?;
+
+    engine.set("a".to_owned(), "changed".to_owned())?;
+    engine.remove("b".to_owned())?;
+    engine.set("e".to_owned(), "new".to_owned())?;
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("value-a".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("value-b".to_owned()));
+    assert_eq!(snapshot.get("e".to_owned())?, None);
+    assert_eq!(
+        keys(snapshot.scan(..)?)?,
+        vec!["a", "ab", "abc", "b", "c", "d"]
+    );
+    assert_eq!(
+        keys(snapshot.scan_prefix("a".to_owned())?)?,
+        vec!["a", "ab", "abc"]
+    );
+
+    // The engine itself moves on
+    assert_eq!(engine.get("a".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(
+        keys(engine.scan(..)?)?,
+        vec!["a", "ab", "abc", "c", "d", "e"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn kvs_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn keyspaces<E: KvsEngine>(engine: E) -> Result<()> {
+    populate(&engine)?;
+    let users = engine.open_tree("users")?;
+    let orders = engine.open_tree("orders")?;
+    assert_eq!(users.get("a".to_owned())?, None);
+
+    users.set("a".to_owned(), "alice".to_owned())?;
+    users.set("b".to_owned(), "bob".to_owned())?;
+    orders.set("a".to_owned(), "1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("c", "carol").remove("b");
+    users.write_batch(batch)?;
+
+    assert_eq!(engine.get("a".to_owned())?, Some("value-a".to_owned()));
+    assert_eq!(users.get("a".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(orders.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(engine.get("c".to_owned())?, Some("value-c".to_owned()));
+    assert_eq!(keys(users.scan(..)?)?, vec!["a", "c"]);
+    assert!(users.compare_and_swap("a".to_owned(), Some("alice".to_owned()), None)?);
+    assert_eq!(engine.get("a".to_owned())?, Some("value-a".to_owned()));
+    assert_eq!(engine.tree_names()?, vec!["orders", "users"]);
+
+    assert!(engine.drop_tree("orders")?);
+    assert!(!engine.drop_tree("orders")?);
+    assert_eq!(orders.get("a".to_owned())?, None);
+    assert_eq!(engine.tree_names()?, vec!["users"]);
+    // Handles on a dropped keyspace can write to it again
+    orders.set("b".to_owned(), "2".to_owned())?;
+    assert_eq!(keys(orders.scan(..)?)?, vec!["b"]);
+
+    for name in &["", "__kvs_expiry"] {
+        match engine.open_tree(name) {
+            Err(KvsError::InvalidTreeName(_)) => {}
+            _ => panic!("keyspace name {:?} accepted", name),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn kvs_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    keyspaces(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.tree_names()?, vec!["orders", "users"]);
+    let users = store.open_tree("users")?;
+    assert_eq!(keys(users.scan(..)?)?, vec!["c"]);
+    assert_eq!(store.open_tree("orders")?.get("a".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    keyspaces(SledKvsEngine::open(temp_dir.path())?)
+}
+
+const DURABILITIES: [Durability; 4] = [
+    Durability::None,
+    Durability::EveryWrite,
+    Durability::Periodic(Duration::from_millis(10)),
+    Durability::GroupCommit,
+];
+
+fn concurrent_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    engine.set(format!("key{}-{}", thread_id, i), format!("{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_durability_settings() -> Result<()> {
+    for &durability in &DURABILITIES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .durability(durability)
+            .max_file_size(1024)
+            .open(temp_dir.path())?;
+        concurrent_writes(store)?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3-49".to_owned())?, Some("49".to_owned()));
+        assert_eq!(store.scan(..)?.count(), 200);
+    }
+    Ok(())
+}
+
+#[test]
+fn sled_durability_settings() -> Result<()> {
+    for &durability in &DURABILITIES {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        concurrent_writes(SledKvsEngine::open_with_durability(
+            temp_dir.path(),
+            durability,
+        )?)?;
+
+        let engine = SledKvsEngine::open(temp_dir.path())?;
+        assert_eq!(engine.get("key3-49".to_owned())?, Some("49".to_owned()));
+        assert_eq!(engine.scan(..)?.count(), 200);
+    }
+    Ok(())
+}
+
+#[test]
+fn durability_names() {
+    for durability in &DURABILITIES {
+        assert_eq!(durability.to_string().parse(), Ok(*durability));
+    }
+    assert_eq!(
+        "periodic:250".parse(),
+        Ok(Durability::Periodic(Duration::from_millis(250)))
+    );
+    assert!("always".parse::<Durability>().is_err());
+    assert!("periodic:0".parse::<Durability>().is_err());
+}
+
+#[test]
+fn kvs_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiring_keys(KvStore::open(temp_dir.path())?)?;
+
+    // Expiry times survive a restart
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("later".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiring_keys(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&[0])?, Some(vec![]));
+    assert_eq!(store.get_bytes(b"key")?, Some(vec![0xff]));
+
+    Ok(())
+}
+
+#[test]
+fn sled_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_keys_and_values(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_in_key_order(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_prefix(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_in_key_order(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_prefix(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn store_metadata_guards_engine_and_format() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(kvs_dir.path())?);
+    let metadata = StoreMetadata::read(kvs_dir.path())?.unwrap();
+    assert_eq!(metadata.engine, "kvs");
+    drop(KvStore::open(kvs_dir.path())?);
+    assert_eq!(
+        StoreMetadata::read(kvs_dir.path())?.unwrap().created_at,
+        metadata.created_at
+    );
+    match SledKvsEngine::open(kvs_dir.path()) {
+        Err(KvsError::EngineMismatch { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("sled", "kvs"))
+        }
+        result => panic!("expected a mismatch, got {:?}", result.map(|_| ())),
+    }
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(sled_dir.path())?);
+    assert_eq!(
+        StoreMetadata::read(sled_dir.path())?.unwrap().engine,
+        "sled"
+    );
+    assert!(matches!(
+        KvStore::open(sled_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+    assert!(!sled_dir.path().join("LOCK").exists());
+
+    let mut too_new = metadata;
+    too_new.format_version += 1;
+    fs::write(
+        kvs_dir.path().join("METADATA"),
+        serde_json::to_vec(&too_new).unwrap(),
+    )?;
+    match KvStore::open(kvs_dir.path()) {
+        Err(KvsError::UnsupportedFormatVersion(version)) => {
+            assert_eq!(version, too_new.format_version)
+        }
+        result => panic!("expected a format error, got {:?}", result.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A sled store that can't be opened, here because it already is, keeps the
+// metadata of the open that has it
+#[test]
+fn sled_metadata_only_recorded_once_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let metadata = fs::read(temp_dir.path().join("METADATA"))?;
+    // sled panics rather than failing when its database is locked
+    let second = panic::catch_unwind(|| {
+        SledKvsEngine::open_with_durability(temp_dir.path(), Durability::None).map(|_| ())
+    });
+    assert!(!matches!(second, Ok(Ok(()))));
+    assert_eq!(fs::read(temp_dir.path().join("METADATA"))?, metadata);
+    drop(engine);
+
+    Ok(())
+}
+
+// The `engine` file kvs-server used to write is honoured, then replaced
+#[test]
+fn legacy_engine_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("engine"), "sled")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+    drop(SledKvsEngine::open(temp_dir.path())?);
+    assert!(!temp_dir.path().join("engine").exists());
+    assert_eq!(
+        StoreMetadata::read(temp_dir.path())?.unwrap().engine,
+        "sled"
+    );
+    Ok(())
+}
@@ -1,4 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsError, KvsSnapshot, Result, StoreMetadata, WriteBatch,
+};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +15,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +25,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +36,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +45,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +57,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +73,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +81,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +93,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +123,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +133,1505 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Concurrent writers through cloned handles should all be persisted
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Concurrent readers should see values written before they started
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    assert_eq!(
+                        store.get(format!("key{}", i)).unwrap(),
+                        Some(format!("value{}", i))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Reads from finished generations, which are memory-mapped, should see the
+// same values across threads, writes to the active log and compactions
+#[test]
+fn concurrent_get_across_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_file_size(1024)
+        .disable_compaction(true)
+        .open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(log_files(temp_dir.path()) > 1);
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    for i in 0..200 {
+                        assert_eq!(
+                            store.get(format!("key{}", i)).unwrap(),
+                            Some(format!("value{}", i))
+                        );
+                    }
+                }
+            })
+        })
+        .collect();
+    for i in 200..250 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..250 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Logs written as serde_json commands should still be readable
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A record whose bytes changed on disk should be detected on open
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut contents = fs::read(&log)?;
+    let position = contents
+        .windows(6)
+        .position(|window| window == b"value1")
+        .expect("value not found in log");
+    contents[position] = b'V';
+    fs::write(&log, contents)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptLog { gen: 1, .. }) => {}
+        other => panic!("expected corrupt log error, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A record cut short by a crash at the end of the newest log should be
+// dropped on open
+#[test]
+fn truncate_torn_tail_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A damaged length in the middle of the newest log must not be mistaken for
+// a torn tail, and the records after it must not be truncated away
+#[test]
+fn damaged_length_is_not_a_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    // The length field of the first record, right after the 12 byte header
+    bytes[19] = 0xFF;
+    fs::write(&log, &bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptLog { gen: 1, offset: 12 }) => {}
+        other => panic!("expected corrupt log error, got {:?}", other),
+    }
+    assert_eq!(fs::read(&log)?, bytes);
+
+    Ok(())
+}
+
+// A torn record whose value happens to hold an intact frame is still a torn
+// tail, since nothing after it lines up with the end of the log
+#[test]
+fn frame_inside_torn_value_is_a_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut value = frame(b"not a record");
+    value.extend_from_slice(b"and more");
+    store.set_bytes(b"key2".to_vec(), value)?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_bytes(b"key2")?, None);
+
+    Ok(())
+}
+
+// Reads and writes should keep working while compactions run in the background
+#[test]
+fn compaction_with_concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for iter in 0..200 {
+                    let key = format!("key{}", iter % 100);
+                    assert!(store.get(key).unwrap().is_some());
+                }
+            })
+        })
+        .collect();
+
+    let value = "x".repeat(1000);
+    for iter in 0..30 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("29{}", value))
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("29{}", value))
+        );
+    }
+
+    Ok(())
+}
+
+// Output from a compaction that never finished should be ignored
+#[test]
+fn ignore_incomplete_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("2.log.compact");
+    fs::write(&leftover, b"KVSL")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!leftover.exists());
+
+    Ok(())
+}
+
+fn log_files(path: &Path) -> usize {
+    fs::read_dir(path)
+        .expect("unable to read store directory")
+        .flatten()
+        .filter(|entry| entry.path().extension() == Some(OsStr::new("log")))
+        .count()
+}
+
+// A lower compaction threshold should shrink the log after fewer writes
+#[test]
+fn builder_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    drop(store);
+
+    // 100 records of ~45 bytes each would be ~4.5 KiB uncompacted
+    let size: u64 = fs::read_dir(temp_dir.path())?
+        .flatten()
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(size < 4096, "log wasn't compacted, size = {}", size);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// With compaction disabled the log should only ever grow
+#[test]
+fn builder_disable_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .disable_compaction(true)
+        .open(temp_dir.path())?;
+
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    drop(store);
+
+    assert_eq!(log_files(temp_dir.path()), 1);
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() > 20 * 1000);
+
+    Ok(())
+}
+
+// The active log should be rotated once it reaches the maximum file size
+#[test]
+fn builder_max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_file_size(1024)
+        .open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_files(temp_dir.path()) > 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+fn hint_files(path: &Path) -> Vec<PathBuf> {
+    fs::read_dir(path)
+        .expect("unable to read store directory")
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some(OsStr::new("hint")))
+        .collect()
+}
+
+// Compaction should leave a hint file that is used on the next open
+#[test]
+fn compaction_writes_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    assert!(!hint_files(temp_dir.path()).is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// A damaged hint file should be ignored in favour of the log itself
+#[test]
+fn ignore_damaged_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let hints = hint_files(temp_dir.path());
+    assert!(!hints.is_empty());
+    for hint in hints {
+        let mut contents = fs::read(&hint)?;
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        fs::write(&hint, contents)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should drop values that have expired
+#[test]
+fn compaction_drops_expired_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+
+    let big_value = "x".repeat(1000);
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            big_value.clone(),
+            Duration::from_millis(100),
+        )?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    for iter in 0..100 {
+        store.set("counter".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let size: u64 = fs::read_dir(temp_dir.path())?
+        .flatten()
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(size < 10 * 1024, "expired values kept, size = {}", size);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("counter".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// A batch cut short by a crash should be dropped as a whole
+#[test]
+fn torn_batch_is_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1", "value2")
+        .set("key2", "value2")
+        .remove("key3");
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Lose the end of the batch, well after its first record
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A snapshot should keep the logs it reads from until it is dropped
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    // Dropping the store waits for the compaction to finish
+    drop(store);
+    assert!(temp_dir.path().join("1.log.retired").exists());
+
+    for key_id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+    }
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log.retired").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Older versions should be readable by sequence number while they're retained
+#[test]
+fn history_survives_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .retain_versions(3)
+        .open(temp_dir.path())?;
+
+    let mut seqs = Vec::new();
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        seqs.push(store.last_seq());
+    }
+    store.remove("key1".to_owned())?;
+    let removed_at = store.last_seq();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    drop(store);
+
+    let store = KvStore::builder()
+        .retain_versions(3)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get_at(b"key1", seqs[99])?, Some(b"value99".to_vec()));
+    assert_eq!(store.get_at(b"key1", seqs[98])?, Some(b"value98".to_vec()));
+    assert_eq!(store.get_at(b"key1", removed_at)?, None);
+    // Long since dropped by compaction
+    assert_eq!(store.get_at(b"key1", seqs[0])?, None);
+
+    let history = store.history(b"key1")?;
+    assert_eq!(
+        history.last().map(|version| version.value.clone()),
+        Some(None)
+    );
+    assert!(history.len() <= 4);
+    assert!(history.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+    Ok(())
+}
+
+// Versions replaced within the retention window should be kept
+#[test]
+fn history_retained_for_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .retain_for(Duration::from_secs(3600))
+        .open(temp_dir.path())?;
+
+    for iter in 0..5 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let values: Vec<_> = store
+        .history(b"key1")?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    let expected: Vec<_> = (0..5)
+        .map(|iter| Some(format!("value{}", iter).into_bytes()))
+        .collect();
+    assert_eq!(values, expected);
+
+    Ok(())
+}
+
+// Without a retention policy only the current version is kept
+#[test]
+fn history_defaults_to_current_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.last_seq();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get_at(b"key1", first)?, None);
+    let history = store.history(b"key1")?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some(b"value2".to_vec()));
+    assert_eq!(history[0].seq, store.last_seq());
+
+    Ok(())
+}
+
+// Sequence numbers never go backwards, even once compaction has dropped the
+// records of the most recent writes and the store is reopened
+#[test]
+fn sequence_survives_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..4 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let last_seq = store.last_seq();
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_seq(), last_seq);
+    store.set("key1".to_owned(), "later".to_owned())?;
+    assert!(store.last_seq() > last_seq);
+    assert_eq!(store.get_at(b"key1", last_seq)?, None);
+    drop(store);
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.last_seq(), last_seq + 1);
+
+    Ok(())
+}
+
+// Compacting one keyspace should only delete logs no other keyspace needs
+#[test]
+fn compact_keyspaces_independently() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .disable_compaction(true)
+        .open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for iter in 0..100 {
+        users.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+
+    users.compact()?;
+    assert!(temp_dir.path().join("1.log").exists());
+    assert_eq!(users.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(users);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    assert_eq!(users.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Values read again should come from the cache, which forgets overwritten
+// values and follows values moved by compaction
+#[test]
+fn value_cache_hits_and_invalidation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().cache_size(1024).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.cache_stats().entries, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.cache_stats().entries, 2);
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (3, 3, 2));
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.cache_stats().entries, 1);
+
+    Ok(())
+}
+
+// The cache should never hold more than its size in values
+#[test]
+fn value_cache_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().cache_size(100).open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{:05}", i))?;
+    }
+    for _ in 0..2 {
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{:05}", i))
+            );
+        }
+    }
+    let stats = store.cache_stats();
+    assert!(stats.bytes <= 100);
+    assert_eq!(stats.entries, 10);
+    assert_eq!(stats.hits + stats.misses, 100);
+
+    // The cache is off by default
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.get("key1".to_owned())?;
+    assert_eq!(store.cache_stats(), kvs::CacheStats::default());
+
+    Ok(())
+}
+
+#[cfg(feature = "lz4")]
+fn log_size(path: &Path) -> u64 {
+    fs::read_dir(path)
+        .expect("unable to read store directory")
+        .flatten()
+        .filter(|entry| entry.path().extension() == Some(OsStr::new("log")))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// Compressed and uncompressed values should be readable side by side, and
+// compaction should compress values written before compression was on
+#[cfg(feature = "lz4")]
+#[test]
+fn compressed_values() -> Result<()> {
+    use kvs::Compression;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let document = |i: usize| {
+        format!(
+            "{{\"id\": {}, \"body\": \"{}\"}}",
+            i,
+            "lorem ipsum ".repeat(50)
+        )
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("plain{}", i), document(i))?;
+    }
+    drop(store);
+    let uncompressed = log_size(temp_dir.path());
+
+    let store = KvStore::builder()
+        .compression(Compression::Lz4)
+        .compression_min_size(16)
+        .open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("packed{}", i), document(i))?;
+    }
+    store.set("short".to_owned(), "tiny".to_owned())?;
+    assert!(log_size(temp_dir.path()) < uncompressed * 3 / 2);
+    for i in 0..20 {
+        assert_eq!(store.get(format!("plain{}", i))?, Some(document(i)));
+        assert_eq!(store.get(format!("packed{}", i))?, Some(document(i)));
+    }
+
+    store.compact()?;
+    assert!(log_size(temp_dir.path()) < uncompressed / 2);
+
+    // Reading doesn't depend on the setting
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("plain{}", i))?, Some(document(i)));
+        assert_eq!(store.get(format!("packed{}", i))?, Some(document(i)));
+    }
+    assert_eq!(store.get("short".to_owned())?, Some("tiny".to_owned()));
+
+    Ok(())
+}
+
+fn test_key(id: u32) -> kvs::EncryptionKey {
+    kvs::EncryptionKey::new(id, [id as u8; 32]).unwrap()
+}
+
+fn store_contains(path: &Path, needle: &[u8]) -> bool {
+    fs::read_dir(path)
+        .expect("unable to read store directory")
+        .flatten()
+        .map(|entry| fs::read(entry.path()).unwrap())
+        .any(|bytes| bytes.windows(needle.len()).any(|window| window == needle))
+}
+
+// Neither keys nor values should reach the disk in plaintext, and the
+// store can't be opened without the key
+#[test]
+fn encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    store.set("token-owner".to_owned(), "secret-token".to_owned())?;
+    store.set("other".to_owned(), "other-value".to_owned())?;
+    store.remove("other".to_owned())?;
+    store.compact()?;
+    store.set("later".to_owned(), "later-value".to_owned())?;
+    drop(store);
+    assert!(!hint_files(temp_dir.path()).is_empty());
+    for plaintext in ["token-owner", "secret-token", "later-value"] {
+        assert!(!store_contains(temp_dir.path(), plaintext.as_bytes()));
+    }
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::MissingEncryptionKey(1)) => {}
+        result => panic!("expected a missing key error, got {:?}", result.map(|_| ())),
+    }
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get("token-owner".to_owned())?,
+        Some("secret-token".to_owned())
+    );
+    assert_eq!(store.get("other".to_owned())?, None);
+    assert_eq!(
+        store.get("later".to_owned())?,
+        Some("later-value".to_owned())
+    );
+
+    Ok(())
+}
+
+// A record whose checksum still matches but whose contents were changed
+// should be reported as tampered with
+#[test]
+fn detect_tampered_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // Flip a bit of the only record and fix up its checksum
+    let log_path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log_path)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    let frame = &mut bytes[12..];
+    let crc = crc32fast::hash(&frame[4..]);
+    frame[..4].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&log_path, bytes)?;
+
+    match KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())
+    {
+        Err(KvsError::Tampered { gen: 1, offset: 12 }) => {}
+        result => panic!("expected a tampering error, got {:?}", result.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// An encrypted batch cut short, here to its first record, should be reported
+// as tampered with even once its checksum is fixed up
+#[test]
+fn detect_truncated_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1").set("key2", "value2");
+    store.write_batch(batch)?;
+    drop(store);
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let bytes = fs::read(&log_path)?;
+    // The batch's kind and flags, then its first record
+    let payload = &bytes[20..];
+    let first_len = u32::from_le_bytes(payload[6..10].try_into().unwrap()) as usize;
+    let mut truncated = bytes[..12].to_vec();
+    truncated.extend_from_slice(&frame(&payload[..10 + first_len]));
+    fs::write(&log_path, truncated)?;
+
+    match KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())
+    {
+        Err(KvsError::Tampered { gen: 1, offset: 12 }) => {}
+        result => panic!("expected a tampering error, got {:?}", result.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A sealed record copied to another place in the log, here to roll a key
+// back to an older value, should be reported as tampered with
+#[test]
+fn detect_replayed_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "100".to_owned())?;
+    store.set("key".to_owned(), "200".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log_path)?;
+    let len = u32::from_le_bytes(bytes[16..20].try_into().unwrap()) as usize;
+    let first = bytes[12..20 + len].to_vec();
+    let offset = bytes.len() as u64;
+    bytes.extend_from_slice(&first);
+    fs::write(&log_path, bytes)?;
+
+    match KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())
+    {
+        Err(KvsError::Tampered {
+            gen: 1,
+            offset: found,
+        }) if found == offset => {}
+        result => panic!("expected a tampering error, got {:?}", result.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Compaction should rewrite data under the current key, after which older
+// keys are no longer needed
+#[test]
+fn rotate_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+
+    let store = KvStore::builder()
+        .encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    store.set("first".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::builder()
+        .encryption_key(test_key(2))
+        .old_encryption_key(test_key(1))
+        .open(temp_dir.path())?;
+    store.set("second".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("first".to_owned())?, Some("value1".to_owned()));
+    store.compact()?;
+    drop(store);
+    assert!(!store_contains(temp_dir.path(), b"value0"));
+
+    let store = KvStore::builder()
+        .encryption_key(test_key(2))
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("first".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("second".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A store can only be open once at a time
+#[test]
+fn store_is_locked_while_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::AlreadyLocked(path)) => assert_eq!(path, temp_dir.path()),
+        result => panic!("expected a lock error, got {:?}", result.map(|_| ())),
+    }
+
+    // Every clone has to go before the lock does
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Read-only stores share a lock while they read the logs, which a writer
+// takes exclusively while it recovers them, so each waits for the other
+#[test]
+fn readers_and_recovery_take_turns() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let read_lock = fs::File::open(temp_dir.path().join("READ_LOCK"))?;
+
+    // A writer recovering the logs holds readers off
+    read_lock.lock()?;
+    let path = temp_dir.path().to_owned();
+    let reader = thread::spawn(move || KvStore::open_read_only(path));
+    thread::sleep(Duration::from_millis(100));
+    assert!(!reader.is_finished());
+    read_lock.unlock()?;
+    let reader = reader.join().unwrap()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Readers only hold the writer off while they are reading
+    let path = temp_dir.path().to_owned();
+    let writer = thread::spawn(move || KvStore::open(path).map(drop));
+    writer.join().unwrap()?;
+    read_lock.lock_shared()?;
+    let path = temp_dir.path().to_owned();
+    let writer = thread::spawn(move || KvStore::open(path).map(drop));
+    thread::sleep(Duration::from_millis(100));
+    assert!(!writer.is_finished());
+    read_lock.unlock()?;
+    writer.join().unwrap()?;
+    reader.refresh()?;
+
+    Ok(())
+}
+
+fn dir_listing(path: &Path) -> Vec<(PathBuf, u64)> {
+    let mut listing: Vec<_> = WalkDir::new(path)
+        .into_iter()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.path().to_owned(), entry.metadata().unwrap().len())
+        })
+        .collect();
+    listing.sort();
+    listing
+}
+
+// A read-only store should read everything a writer left, and never change
+// anything in the directory
+#[test]
+fn read_only_store_never_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+
+    let store = KvStore::builder().max_file_size(64).open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    let listing = dir_listing(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key19".to_owned())?, Some("value19".to_owned()));
+    assert!(matches!(
+        store.set("key1".to_owned(), "changed".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Readers share the directory
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    drop(other);
+    assert_eq!(dir_listing(temp_dir.path()), listing);
+
+    Ok(())
+}
+
+// A read-only store should pick up a writer's appends and compactions on
+// refresh, and carry on across the writer being restarted
+#[test]
+fn read_only_store_follows_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = KvStore::builder()
+        .disable_compaction(true)
+        .open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    writer.remove("key1".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Compaction replaces the logs the reader loaded
+    for i in 0..100 {
+        writer.set("key2".to_owned(), format!("value{}", i))?;
+    }
+    writer.compact()?;
+    writer.set("key3".to_owned(), "value3".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(reader.last_seq(), writer.last_seq());
+
+    // A writer that crashed mid-append is restarted, and recovers, under
+    // the reader
+    drop(writer);
+    let newest = *manifest_generations(temp_dir.path()).last().unwrap();
+    let log = temp_dir.path().join(format!("{}.log", newest));
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(&[1, 2, 3, 4, 5])?;
+    reader.refresh()?;
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("key4".to_owned(), "value4".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(reader.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+fn manifest_generations(path: &Path) -> Vec<u64> {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(path.join("MANIFEST")).unwrap()).unwrap();
+    serde_json::from_value(manifest["generations"].clone()).unwrap()
+}
+
+// Files that look like logs but aren't in the manifest, or like files the
+// store leaves behind, are never loaded or deleted, unless they are logs
+// older than every live generation
+#[test]
+fn manifest_decides_live_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    drop(store);
+    assert_eq!(manifest_generations(temp_dir.path()), vec![1]);
+
+    let stray = temp_dir.path().join("7.log");
+    fs::write(&stray, b"not a log")?;
+    let backup = temp_dir.path().join("1.log.bak");
+    fs::copy(temp_dir.path().join("1.log"), &backup)?;
+    let lookalikes: Vec<_> = [
+        "notes.tmp",
+        "backup.log.retired",
+        "x.compact",
+        "x.log.compact",
+    ]
+    .iter()
+    .map(|name| temp_dir.path().join(name))
+    .collect();
+    for lookalike in &lookalikes {
+        fs::write(lookalike, b"not the store's")?;
+    }
+    // Whereas these are the store's own
+    let leftovers: Vec<_> = [
+        "MANIFEST.tmp",
+        "3.log.compact",
+        "3.hint.compact",
+        "2.log.retired",
+    ]
+    .iter()
+    .map(|name| temp_dir.path().join(name))
+    .collect();
+    for leftover in &leftovers {
+        fs::write(leftover, b"")?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    assert!(lookalikes.iter().all(|lookalike| lookalike.exists()));
+    assert!(!leftovers.iter().any(|leftover| leftover.exists()));
+    // New generations are numbered past the stray one
+    store.compact()?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(manifest_generations(temp_dir.path()), vec![9, 10]);
+    assert!(stray.exists() && backup.exists());
+    drop(store);
+
+    // Logs older than every live generation are ones a compaction dropped
+    // but failed to delete, so they go, along with their hints
+    let dropped = temp_dir.path().join("3.log");
+    let dropped_hint = temp_dir.path().join("3.hint");
+    fs::copy(&backup, &dropped)?;
+    fs::write(&dropped_hint, b"")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    assert!(!stray.exists() && !dropped.exists() && !dropped_hint.exists());
+    assert!(backup.exists());
+
+    Ok(())
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len);
+    hasher.update(payload);
+    let mut frame = hasher.finalize().to_le_bytes().to_vec();
+    frame.extend_from_slice(&len);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A set or remove frame, laid out as format `version` had it.
+fn old_record(
+    version: u32,
+    tree: &str,
+    key: &str,
+    value: Option<&str>,
+    expires_at: Option<u64>,
+) -> Vec<u8> {
+    let kind = if value.is_some() { 1 } else { 2 };
+    let mut flags = 0;
+    if expires_at.is_some() {
+        flags |= 1;
+    }
+    if version >= 4 {
+        flags |= 2;
+    }
+    if !tree.is_empty() {
+        flags |= 4;
+    }
+    let mut payload = vec![kind, flags];
+    if version >= 4 {
+        payload.extend_from_slice(&0u64.to_le_bytes());
+        payload.extend_from_slice(&1_000u64.to_le_bytes());
+    }
+    if let Some(expires_at) = expires_at {
+        payload.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    let fields = [tree, key, value.unwrap_or("")];
+    let fields = if tree.is_empty() {
+        &fields[1..]
+    } else {
+        &fields[..]
+    };
+    let fields = if value.is_some() {
+        fields
+    } else {
+        &fields[..fields.len() - 1]
+    };
+    for field in fields {
+        payload.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        payload.extend_from_slice(field.as_bytes());
+    }
+    frame(&payload)
+}
+
+/// Write a log in format `version` using each feature it introduced, then
+/// check it reads back, before and after upgrading.
+fn read_old_format(version: u32) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"KVSL".to_vec();
+    log.extend_from_slice(&version.to_le_bytes());
+    log.extend(old_record(version, "", "a", Some("1"), None));
+    log.extend(old_record(version, "", "b", Some("2"), None));
+    log.extend(old_record(version, "", "b", None, None));
+    if version >= 2 {
+        // Long after any test run
+        log.extend(old_record(version, "", "c", Some("3"), Some(u64::MAX / 2)));
+    }
+    if version >= 3 {
+        let mut batch = vec![3, 0];
+        batch.extend(old_record(version, "", "d", Some("4"), None));
+        batch.extend(old_record(version, "", "a", None, None));
+        log.extend(frame(&batch));
+    }
+    if version >= 5 {
+        log.extend(old_record(version, "t", "e", Some("5"), None));
+    }
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let expected_a = if version >= 3 {
+            None
+        } else {
+            Some("1".to_owned())
+        };
+        assert_eq!(store.get("a".to_owned())?, expected_a);
+        assert_eq!(store.get("b".to_owned())?, None);
+        let expected_c = if version >= 2 {
+            Some("3".to_owned())
+        } else {
+            None
+        };
+        assert_eq!(store.get("c".to_owned())?, expected_c);
+        let expected_d = if version >= 3 {
+            Some("4".to_owned())
+        } else {
+            None
+        };
+        assert_eq!(store.get("d".to_owned())?, expected_d);
+        let expected_e = if version >= 5 {
+            Some("5".to_owned())
+        } else {
+            None
+        };
+        assert_eq!(store.open_tree("t")?.get("e".to_owned())?, expected_e);
+        Ok(())
+    };
+    check(&KvStore::open(temp_dir.path())?)?;
+    KvStore::upgrade(temp_dir.path(), KvStoreOptions::new(), false)?;
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Logs written in every older binary format should still be readable
+#[test]
+fn read_format_v1() -> Result<()> {
+    read_old_format(1)
+}
+
+#[test]
+fn read_format_v2() -> Result<()> {
+    read_old_format(2)
+}
+
+#[test]
+fn read_format_v3() -> Result<()> {
+    read_old_format(3)
+}
+
+#[test]
+fn read_format_v4() -> Result<()> {
+    read_old_format(4)
+}
+
+#[test]
+fn read_format_v5() -> Result<()> {
+    read_old_format(5)
+}
+
+#[test]
+fn read_format_v6() -> Result<()> {
+    read_old_format(6)
+}
+
+// Upgrading should rewrite a store of legacy JSON logs in the current format,
+// and only report what it would do in a dry run
+#[test]
+fn upgrade_legacy_json_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy =
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#;
+    fs::write(temp_dir.path().join("1.log"), legacy)?;
+
+    // A dry run changes nothing
+    let report = KvStore::upgrade(temp_dir.path(), KvStoreOptions::new(), true)?;
+    assert_eq!(report.outdated, vec![(1, 0)]);
+    assert_eq!(report.from_version, 0);
+    assert_eq!(dir_listing(temp_dir.path()).len(), 2);
+
+    // Opening records the old format without rewriting anything
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert_eq!(
+        StoreMetadata::read(temp_dir.path())?
+            .unwrap()
+            .format_version,
+        0
+    );
+    assert_eq!(fs::read_to_string(temp_dir.path().join("1.log"))?, legacy);
+
+    let report = KvStore::upgrade(temp_dir.path(), KvStoreOptions::new(), false)?;
+    assert_eq!(report.outdated, vec![(1, 0)]);
+    assert!(!temp_dir.path().join("1.log").exists());
+    let metadata = StoreMetadata::read(temp_dir.path())?.unwrap();
+    assert_eq!(metadata.format_version, report.to_version);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+    let report = KvStore::upgrade(temp_dir.path(), KvStoreOptions::new(), true)?;
+    assert!(report.outdated.is_empty());
+    assert_eq!(report.from_version, report.to_version);
+
+    Ok(())
+}
+
+// Upgrading should keep the history the store retains, and refuse options
+// that would compact it away
+#[test]
+fn upgrade_store_with_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"KVSL".to_vec();
+    log.extend_from_slice(&6u32.to_le_bytes());
+    for value in &["1", "2", "3"] {
+        log.extend(old_record(6, "", "key", Some(value), None));
+    }
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+    let history = |store: &KvStore| -> Result<Vec<Option<Vec<u8>>>> {
+        Ok(store
+            .history(b"key")?
+            .into_iter()
+            .map(|version| version.value)
+            .collect())
+    };
+    let values = vec![
+        Some(b"1".to_vec()),
+        Some(b"2".to_vec()),
+        Some(b"3".to_vec()),
+    ];
+    let store = KvStore::builder()
+        .retain_versions(5)
+        .disable_compaction(true)
+        .open(temp_dir.path())?;
+    assert_eq!(history(&store)?, values);
+    drop(store);
+
+    for dry_run in [true, false] {
+        match KvStore::upgrade(temp_dir.path(), KvStoreOptions::new(), dry_run) {
+            Err(KvsError::HistoryNotRetained(_)) => {}
+            result => panic!("expected a history error, got {:?}", result),
+        }
+    }
+    assert_eq!(fs::read(temp_dir.path().join("1.log"))?, log);
+
+    let options = KvStoreOptions::recorded(temp_dir.path())?;
+    let report = KvStore::upgrade(temp_dir.path(), options, false)?;
+    assert_eq!(report.outdated, vec![(1, 6)]);
+    let metadata = StoreMetadata::read(temp_dir.path())?.unwrap();
+    assert_eq!(metadata.options["retain_versions"], "5");
+    let store = KvStore::builder()
+        .retain_versions(5)
+        .open(temp_dir.path())?;
+    assert_eq!(history(&store)?, values);
+
+    Ok(())
+}
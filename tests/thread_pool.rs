@@ -0,0 +1,63 @@
+use kvs::{KvsError, NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+const JOBS: usize = 20;
+
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..JOBS {
+        let counter = counter.clone();
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..JOBS {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("job did not run");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), JOBS);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+// Panicking jobs should not shrink the pool
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..JOBS {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    spawn_counter(pool)
+}
+
+// A pool without threads would never run anything
+#[test]
+fn thread_pools_reject_zero_threads() {
+    assert!(matches!(NaiveThreadPool::new(0), Err(KvsError::NoThreads)));
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::NoThreads)
+    ));
+    assert!(matches!(RayonThreadPool::new(0), Err(KvsError::NoThreads)));
+}